@@ -0,0 +1,119 @@
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::sync::RwLock;
+use once_cell::sync::Lazy;
+use super::trade::{StaticStr, TRADES, TradeManager};
+use super::store::BACKEND;
+use super::config;
+use super::error::{LedgerError, LedgerResult};
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum AssetStatus {
+    Active,
+    Retired,                                    //下线的资产 历史交易保留 不再接受新的交易
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AssetInfo {
+    pub name: StaticStr,
+    pub decimals: u8,
+    pub status: AssetStatus,
+}
+
+impl AssetInfo {
+    pub fn new(name: StaticStr, decimals: u8)-> Self {
+        Self{name, decimals, status: AssetStatus::Active}
+    }
+}
+
+const ASSETS_KEY: &str = "@assets";
+pub(crate) const DEFAULT_ASSETS: [(&str, u8); 7] = [("BTC_ASSET_ID", 8), ("rgb:7Yjbbk!p-Dl4GOJG-Z2ct!BU-yJ2Ji8I-z13MdSL-QAklonM", 0),
+    ("rgb:o2PKHzYo-YVviDw7-LKUJAPH-ARrmVW0-aQndBsH-WJJ2540", 0), ("rgb:P1Jy$7jt-5ezm74W-SSlIuCW-axO9dfV-$9TPimE-gex6l$8", 0),
+    ("rgb:!BmcPbfz-BpQWa0Q-qsmVlp0-VV12tvx-I2WkNz3-D!dGFmw", 0), ("rgb:RspPWEW9-mzuSNHQ-dGCb054-bLjHPYi-$I9$Ih2-Fy9vxFU", 0),
+    ("rgb:VNyUso5w-6rx1FoB-kODxlFs-$Ej0BJP-aIsyDMs-acdufQs", 0)];          //默认配置中的资产 资产的 id 就是在列表中的位置 不能改变顺序
+
+pub struct AssetRegistry {
+    assets: RwLock<Vec<AssetInfo>>,
+}
+
+fn decode(list: Vec<Vec<u8>>)-> LedgerResult<Vec<AssetInfo>> {        //资产 id 就是位置 跳过无法解析的会让之后的资产全部错位
+    list.iter().enumerate().map(|(i, buf)| rmp_serde::from_slice(buf).map_err(|e| LedgerError::StorageFailure(format!("decode asset {} {}", i, e))) ).collect()
+}
+
+pub fn verify_stored()-> LedgerResult<()> {     //config::init 在加载资产之前调用 启动时返回错误
+    decode(BACKEND.meta().list(ASSETS_KEY)).map(|_| () )
+}
+
+impl AssetRegistry {
+    fn load()-> Self {
+        let meta = BACKEND.meta();
+        let mut assets = decode(meta.list(ASSETS_KEY)).unwrap_or_else(|e| panic!("{}", e) );
+        if assets.is_empty() {
+            for entry in config::get().assets.iter() {          //首次启动时写入配置中的资产
+                let info = AssetInfo::new(Cow::from(entry.name.clone()), entry.decimals);
+                meta.push(ASSETS_KEY, &rmp_serde::to_vec(&info).unwrap());
+                assets.push(info);
+            }
+        }
+        Self{assets: RwLock::new(assets)}
+    }
+
+    pub fn len(&self)-> usize {
+        self.assets.read().unwrap().len()
+    }
+    pub fn is_empty(&self)-> bool {
+        self.len() == 0
+    }
+    pub fn get(&self, asset: u32)-> Option<AssetInfo> {
+        self.assets.read().unwrap().get(asset as usize).cloned()
+    }
+    pub fn list(&self)-> Vec<AssetInfo> {
+        self.assets.read().unwrap().clone()
+    }
+    pub fn position(&self, name: &str)-> Option<usize> {
+        self.assets.read().unwrap().iter().position(|a| a.name == name )
+    }
+
+    fn add(&self, info: AssetInfo)-> LedgerResult<u32> {
+        let mut assets = self.assets.write().unwrap();
+        if assets.iter().any(|a| a.name == info.name ) { return Err(LedgerError::DuplicateAsset(info.name)); }
+        if !BACKEND.meta().push(ASSETS_KEY, &rmp_serde::to_vec(&info)?) { return Err(LedgerError::StorageFailure(format!("store asset {}", info.name))); }
+        assets.push(info);
+        Ok(assets.len() as u32 - 1)
+    }
+
+    fn retire(&self, asset: u32)-> LedgerResult<()> {
+        let mut assets = self.assets.write().unwrap();
+        let info = assets.get_mut(asset as usize).ok_or(LedgerError::UnknownAsset(Cow::from(asset.to_string())))?;
+        let mut retired = info.clone();
+        retired.status = AssetStatus::Retired;
+        if !BACKEND.meta().set_at(ASSETS_KEY, asset as usize, &rmp_serde::to_vec(&retired)?) { return Err(LedgerError::StorageFailure(format!("store asset {}", asset))); }
+        *info = retired;
+        Ok(())
+    }
+}
+
+pub static ASSETS: Lazy<AssetRegistry> = Lazy::new(AssetRegistry::load);
+
+pub fn add_asset(name: StaticStr, decimals: u8)-> LedgerResult<u32> {      //运行时增加资产 同时创建对应的 TradeManager
+    let mut trades = TRADES.write().unwrap();
+    let asset = ASSETS.add(AssetInfo::new(name.clone(), decimals))?;
+    trades.push(std::sync::Arc::new(TradeManager::new(asset, BACKEND.trades(name))));
+    Ok(asset)
+}
+
+pub fn retire_asset(asset: u32)-> LedgerResult<()> {
+    ASSETS.retire(asset)
+}
+
+pub fn list_assets()-> Vec<AssetInfo> {
+    ASSETS.list()
+}
+
+pub fn check_active(asset: u32)-> LedgerResult<()> {              //新的交易只能使用 Active 的资产
+    match ASSETS.get(asset) {
+        Some(info) if info.status == AssetStatus::Active=> Ok(()),
+        Some(info)=> Err(LedgerError::RetiredAsset(info.name)),
+        None=> Err(LedgerError::UnknownAsset(Cow::from(asset.to_string()))),
+    }
+}
@@ -0,0 +1,193 @@
+use std::borrow::Cow;
+use std::io::Write;
+use anyhow::{anyhow, Result};
+use account::{asset, config, expiry, import, reconcile, trade, transaction, WARNINGS};
+
+const USAGE: &str = "usage: account-admin [--config <file>] [--store <redis://..|sled:path|memory>] <command> [args]
+commands:
+    balance <account>
+    trades <asset> <account> [--asc]
+    trade-show <asset> <trade_id>
+    import-mysql <mysql_url> <table> [quarantine_file] [--airdrop] [--dry-run]
+    load-stats
+    warnings
+    expiry
+    transactions [--pending]
+    export [file]
+    repair [--confirm]
+    clean-up --confirm";
+
+struct Args {
+    positional: Vec<String>,
+    flags: Vec<String>,
+}
+
+impl Args {
+    fn parse(args: impl Iterator<Item = String>)-> Result<(Option<String>, Option<String>, Self)> {       //只有 --config --store 带参数 其他 -- 开头的都是开关
+        let (mut config, mut store) = (None, None);
+        let mut positional = Vec::new();
+        let mut flags = Vec::new();
+        let mut args = args.peekable();
+        while let Some(arg) = args.next() {
+            if arg == "--config" { config = Some(args.next().ok_or(anyhow!("--config needs a value"))?); }
+            else if arg == "--store" { store = Some(args.next().ok_or(anyhow!("--store needs a value"))?); }
+            else if let Some(flag) = arg.strip_prefix("--") { flags.push(flag.to_string()); }
+            else { positional.push(arg); }
+        }
+        Ok((config, store, Self{positional, flags}))
+    }
+    fn get(&self, index: usize, name: &str)-> Result<&str> {
+        self.positional.get(index).map(|s| s.as_str() ).ok_or(anyhow!("missing <{}>\n{}", name, USAGE))
+    }
+    fn flag(&self, name: &str)-> bool {
+        self.flags.iter().any(|f| f == name )
+    }
+}
+
+fn asset_id(name: &str)-> Result<u32> {           //可以是 id 也可以是资产名
+    match name.parse() {
+        Ok(id)=> Ok(id),
+        Err(_)=> Ok(account::get_asset_id(name)? as u32),
+    }
+}
+
+fn load()-> tokio::runtime::Runtime {
+    eprintln!("load all {:?}", account::load_all());
+    tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap()
+}
+
+fn balance(args: &Args)-> Result<()> {
+    let account = Cow::from(args.get(1, "account")?.to_string());
+    let amounts = load().block_on(account::get_amount(&account)).ok_or(anyhow!("unknow account {}", account))?;
+    let assets = asset::list_assets();
+    for (asset, (available, locked)) in amounts.into_iter().enumerate() {
+        if (available, locked) == (0, 0) { continue }
+        let name = assets.get(asset).map(|a| a.name.to_string() ).unwrap_or_default();
+        println!("{}\t{}\tavailable {}\tlocked {}", asset, name, available, locked);
+    }
+    Ok(())
+}
+
+fn trades(args: &Args)-> Result<()> {
+    let asset = asset_id(args.get(1, "asset")?)?;
+    let account = Cow::from(args.get(2, "account")?.to_string());
+    for (id, trade) in load().block_on(account::get_trades(asset, &account, !args.flag("asc")))? {
+        println!("{}\t{:?}\t{:?}\t{} -> {}\t{}\t{}", id, trade.r#type, trade.status, trade.from, trade.to, trade.amount, trade.create_tick);
+    }
+    Ok(())
+}
+
+fn trade_show(args: &Args)-> Result<()> {         //直接读取 store 不需要加载账本
+    let asset = asset_id(args.get(1, "asset")?)?;
+    let trade_id = Cow::from(args.get(2, "trade_id")?.to_string());
+    let trade = trade::manager(asset)?.store.get(&trade_id).ok_or(anyhow!("unknow trade {}", trade_id))?;
+    println!("{}", serde_json::to_string_pretty(&trade)?);
+    Ok(())
+}
+
+fn import_mysql(args: &Args)-> Result<()> {       //--dry-run 只统计不写入 被拒绝的行写入 quarantine_file 默认输出到 stderr
+    let url = args.get(1, "mysql_url")?;
+    let table = args.get(2, "table")?;
+    let kind = if args.flag("airdrop") { import::Table::AirDrop } else { import::Table::Transfers };
+    let report = import::import_mysql(url, table, kind, args.flag("dry-run"))?;
+    let mut out: Box<dyn Write> = match args.positional.get(3) {
+        Some(path)=> Box::new(std::fs::File::create(path)?),
+        None=> Box::new(std::io::stderr()),
+    };
+    for q in report.quarantine.iter() {
+        writeln!(out, "{}\t{}", q.reason, q.row)?;
+    }
+    println!("{}", report);
+    Ok(())
+}
+
+fn load_stats()-> Result<()> {
+    let report = load().block_on(reconcile::reconcile());
+    for (asset, manager) in trade::managers().into_iter().enumerate() {
+        println!("asset {}\ttrades {}", asset, manager.store.len());
+    }
+    println!("accounts {}\ttrades {}\twarnings {}\tmismatches {}", report.accounts, report.trades, WARNINGS.len(), report.mismatches.len());
+    Ok(())
+}
+
+fn warnings()-> Result<()> {                    //加载时余额不足的交易
+    let _ = load();
+    WARNINGS.scan(|(asset, account)| println!("{}\t{}", asset, account) );
+    Ok(())
+}
+
+fn expiry()-> Result<()> {                      //超时处理的记录
+    for r in expiry::list_expiry() {
+        println!("{}\t{}\t{:?}\t{:?}\t{}\t{}", r.asset, r.trade_id, r.r#type, r.action, r.reason, r.tick);
+    }
+    Ok(())
+}
+
+fn transactions(args: &Args)-> Result<()> {     //多笔交易的事务 Pending 的需要 commit 或者 abort
+    for t in transaction::list_transactions(args.flag("pending")) {
+        let legs: Vec<String> = t.legs.iter().map(|(asset, id)| format!("{}:{}", asset, id) ).collect();
+        println!("{}\t{:?}\t{}\t{}\t{}", t.id, t.status, legs.join(","), t.create_tick, t.update_tick);
+    }
+    Ok(())
+}
+
+fn export(args: &Args)-> Result<()> {           //每行一个 json {asset, trade_id, trade} 按照插入顺序
+    let mut out: Box<dyn Write> = match args.positional.get(1) {
+        Some(path)=> Box::new(std::io::BufWriter::new(std::fs::File::create(path)?)),
+        None=> Box::new(std::io::BufWriter::new(std::io::stdout())),
+    };
+    let mut result = Ok(());
+    for (asset, manager) in trade::managers().into_iter().enumerate() {
+        manager.store.load_all(&mut |trade_id, trade| {
+            if result.is_ok() {
+                result = serde_json::to_writer(&mut out, &serde_json::json!({"asset": asset, "trade_id": trade_id, "trade": trade})).map_err(anyhow::Error::from)
+                    .and_then(|_| Ok(out.write_all(b"\n")?) );
+            }
+        })?;
+    }
+    result?;
+    Ok(out.flush()?)
+}
+
+fn repair(args: &Args)-> Result<()> {           //没有 --confirm 只列出孤儿数据
+    let fix = args.flag("confirm");
+    for (asset, manager) in trade::managers().into_iter().enumerate() {
+        let repair = manager.store.repair(fix)?;
+        for id in repair.unlisted.iter() { println!("{}\tunlisted\t{}", asset, id); }
+        for id in repair.missing.iter() { println!("{}\tmissing\t{}", asset, id); }
+    }
+    if !fix { println!("run again with --confirm to fix"); }
+    Ok(())
+}
+
+fn clean_up(args: &Args)-> Result<()> {
+    if !args.flag("confirm") {
+        return Err(anyhow!("clean-up deletes every trade in the store, run again with --confirm"));
+    }
+    import::clean_up();
+    println!("cleaned");
+    Ok(())
+}
+
+fn main()-> Result<()> {
+    let (path, store, args) = Args::parse(std::env::args().skip(1))?;
+    let mut conf = config::load(path.as_deref())?;
+    if let Some(store) = store {
+        conf.store = store;
+    }
+    config::init(conf)?;
+    match args.positional.first().map(|c| c.as_str() ) {
+        Some("balance")=> balance(&args),
+        Some("trades")=> trades(&args),
+        Some("trade-show")=> trade_show(&args),
+        Some("import-mysql")=> import_mysql(&args),
+        Some("load-stats")=> load_stats(),
+        Some("warnings")=> warnings(),
+        Some("expiry")=> expiry(),
+        Some("transactions")=> transactions(&args),
+        Some("export")=> export(&args),
+        Some("repair")=> repair(&args),
+        Some("clean-up")=> clean_up(&args),
+        _=> Err(anyhow!("{}", USAGE)),
+    }
+}
@@ -0,0 +1,73 @@
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use account::{config, expiry, rpc, snapshot};
+
+const DEFAULT_ADDR: &str = "127.0.0.1:7878";
+
+async fn serve(stream: TcpStream, mut shutdown: watch::Receiver<bool>) {        //一个连接 按行读取请求 按顺序返回
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    loop {
+        let line = tokio::select! {
+            line = lines.next_line()=> line,
+            _ = shutdown.changed()=> break,
+        };
+        match line {
+            Ok(Some(line)) if line.trim().is_empty()=> continue,
+            Ok(Some(line))=> {
+                let mut response = rpc::handle(&line).await;
+                response.push('\n');
+                if writer.write_all(response.as_bytes()).await.is_err() { break }
+            }
+            _=> break,
+        }
+    }
+}
+
+async fn run(addr: String)-> anyhow::Result<()> {
+    let listener = TcpListener::bind(&addr).await?;
+    let snapshots = match config::get().snapshot_interval {          //崩溃之后从最近的快照开始重放
+        0=> None,
+        interval=> Some(snapshot::spawn_snapshots(std::time::Duration::from_secs(interval))),
+    };
+    log::info!("listen on {}", addr);
+    let (tx, rx) = watch::channel(false);
+    let expiry = expiry::spawn_expiry(rx.clone());
+    let tx = Arc::new(tx);
+    let mut connections = tokio::task::JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept()=> match accepted {
+                Ok((stream, peer))=> {
+                    log::info!("connect {}", peer);
+                    connections.spawn(serve(stream, rx.clone()));
+                }
+                Err(e)=> log::error!("accept {:?}", e),
+            },
+            _ = tokio::signal::ctrl_c()=> break,
+        }
+    }
+    log::info!("shutting down {} connections", connections.len());
+    if let Some(snapshots) = snapshots { snapshots.abort(); }
+    let _ = tx.send(true);                  //正在处理的请求会完成 之后连接关闭
+    while connections.join_next().await.is_some() {}
+    if let Some(expiry) = expiry {          //等待正在进行的检查完成 快照才包含解锁之后的余额
+        if let Err(e) = expiry.await { log::error!("expiry {:?}", e); }
+    }
+    if !snapshot::take_snapshot().await { log::error!("snapshot not stored"); }
+    Ok(())
+}
+
+fn main()-> anyhow::Result<()> {           //account-server [addr] [store] 其他配置来自 ACCOUNT_CONFIG 指定的文件和 ACCOUNT_* 环境变量
+    let mut args = std::env::args().skip(1);
+    let addr = args.next().unwrap_or(DEFAULT_ADDR.to_string());
+    let mut conf = config::load(None)?;
+    if let Some(store) = args.next() {
+        conf.store = store;
+    }
+    config::init(conf)?;
+    log::info!("load all {:?}", account::load_all());
+    tokio::runtime::Builder::new_multi_thread().enable_all().build()?.block_on(run(addr))
+}
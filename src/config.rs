@@ -0,0 +1,231 @@
+use anyhow::{anyhow, Context, Result};
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use snowflaker::generator::Constants;
+use super::asset::{self, DEFAULT_ASSETS};
+use super::expiry::ExpiryAction;
+use super::fee::FeeRule;
+use super::id;
+use super::trade::TransferType;
+use super::store::{self, StoreConfig};
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub store: String,                          //redis://... sled:<path> memory
+    pub key_prefix: String,                     //所有 key 的前缀 staging 和 production 共用一个 redis 时使用
+    pub accounts: SystemAccounts,
+    pub system_assets: SystemAssets,
+    pub assets: Vec<AssetEntry>,                //首次启动写入 之后必须和 store 中的顺序一致 可以在末尾追加
+    pub log: LogConfig,
+    pub wal: Option<String>,                    //本地 write-ahead log 目录 None 不记录
+    pub snapshot_interval: u64,                 //account-server 定期保存快照的间隔 秒 0 只在退出时保存
+    pub center_id: u64,                         //snowflake 的 data center id 和 worker id 同时运行的进程必须不同 0..=31
+    pub worker_id: u64,
+    pub expiry: ExpiryConfig,
+    pub fees: Vec<FeeRule>,                     //add_pay_with_fee add_withdraw_with_fee 使用
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExpiryConfig {                       //长时间 Pending 的 Pay 和 Withdraw
+    pub interval: u64,                          //检查间隔 秒
+    pub action: ExpiryAction,
+    pub rules: Vec<ExpiryRule>,                 //没有规则不检查
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExpiryRule {
+    #[serde(default)]
+    pub asset: Option<u32>,                     //None 匹配所有资产
+    #[serde(default)]
+    pub r#type: Option<TransferType>,           //None 匹配所有类型
+    pub timeout: u64,                           //秒
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SystemAccounts {
+    pub withdraw: String,                       //提现到自己地址时的入账地址
+    pub fund: String,                           //充值的来源 只出现在 journal 里面
+    pub gas_receive: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SystemAssets {                       //资产 id 也就是 assets 中的下标
+    pub btc: u32,
+    pub rna: u32,
+    pub jerry: u32,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AssetEntry {
+    pub name: String,
+    #[serde(default)]
+    pub decimals: u8,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub level: String,                          //off error warn info debug trace
+    pub file: Option<String>,                   //None 输出到 stderr
+}
+
+impl Default for Config {
+    fn default()-> Self {
+        Self{store: "redis://127.0.0.1".to_string(), key_prefix: String::new(), accounts: SystemAccounts::default(), system_assets: SystemAssets::default(),
+            assets: DEFAULT_ASSETS.iter().map(|(name, decimals)| AssetEntry{name: name.to_string(), decimals: *decimals} ).collect(), log: LogConfig::default(), wal: None, snapshot_interval: 600,
+            center_id: Constants::DEFAULT_DATA_CENTER_ID, worker_id: Constants::DEFAULT_WORKER_ID, expiry: ExpiryConfig::default(), fees: Vec::new()}
+    }
+}
+
+impl Default for SystemAccounts {
+    fn default()-> Self {
+        Self{withdraw: "use_to_receive_withdraw_asset".to_string(), fund: "use_to_send_fund_asset".to_string(), gas_receive: "bc1qljz0dldnml3y897n68jxtnycyy62szlpn2mh9a".to_string()}
+    }
+}
+
+impl Default for SystemAssets {
+    fn default()-> Self {
+        Self{btc: 0, rna: 2, jerry: 5}
+    }
+}
+
+impl Default for ExpiryConfig {
+    fn default()-> Self {
+        Self{interval: 60, action: ExpiryAction::Flag, rules: Vec::new()}
+    }
+}
+
+impl Default for LogConfig {
+    fn default()-> Self {
+        Self{level: "info".to_string(), file: None}
+    }
+}
+
+const ENV_OVERRIDES: [&str; 14] = ["ACCOUNT_STORE", "ACCOUNT_KEY_PREFIX", "ACCOUNT_WITHDRAW_ADDR", "ACCOUNT_FUND_ADDR", "ACCOUNT_GAS_RECEIVE_ADDR",
+    "ACCOUNT_ASSET_BTC", "ACCOUNT_ASSET_RNA", "ACCOUNT_ASSET_JERRY", "ACCOUNT_LOG_LEVEL", "ACCOUNT_LOG_FILE", "ACCOUNT_WAL", "ACCOUNT_SNAPSHOT_INTERVAL",
+    "ACCOUNT_CENTER_ID", "ACCOUNT_WORKER_ID"];
+
+impl Config {
+    pub fn parse(path: &str, content: &str)-> Result<Self> {          //按照扩展名 .json 使用 json 其他都按 toml 解析
+        if path.ends_with(".json") {
+            serde_json::from_str(content).with_context(|| format!("config {}", path) )
+        } else {
+            toml::from_str(content).with_context(|| format!("config {}", path) )
+        }
+    }
+
+    fn override_with(&mut self, key: &str, value: String)-> Result<()> {
+        let id = |value: &str| value.parse::<u32>().map_err(|_| anyhow!("{} must be an asset id, got {}", key, value) );
+        match key {
+            "ACCOUNT_STORE"=> self.store = value,
+            "ACCOUNT_KEY_PREFIX"=> self.key_prefix = value,
+            "ACCOUNT_WITHDRAW_ADDR"=> self.accounts.withdraw = value,
+            "ACCOUNT_FUND_ADDR"=> self.accounts.fund = value,
+            "ACCOUNT_GAS_RECEIVE_ADDR"=> self.accounts.gas_receive = value,
+            "ACCOUNT_ASSET_BTC"=> self.system_assets.btc = id(&value)?,
+            "ACCOUNT_ASSET_RNA"=> self.system_assets.rna = id(&value)?,
+            "ACCOUNT_ASSET_JERRY"=> self.system_assets.jerry = id(&value)?,
+            "ACCOUNT_LOG_LEVEL"=> self.log.level = value,
+            "ACCOUNT_LOG_FILE"=> self.log.file = Some(value),
+            "ACCOUNT_WAL"=> self.wal = Some(value),
+            "ACCOUNT_SNAPSHOT_INTERVAL"=> self.snapshot_interval = value.parse().map_err(|_| anyhow!("{} must be seconds, got {}", key, value))?,
+            "ACCOUNT_CENTER_ID"=> self.center_id = value.parse().map_err(|_| anyhow!("{} must be a number, got {}", key, value))?,
+            "ACCOUNT_WORKER_ID"=> self.worker_id = value.parse().map_err(|_| anyhow!("{} must be a number, got {}", key, value))?,
+            _=> {}
+        }
+        Ok(())
+    }
+
+    pub fn store_config(&self)-> Result<StoreConfig> {
+        self.store.parse()
+    }
+
+    pub fn log_level(&self)-> Result<log::LevelFilter> {
+        self.log.level.parse().map_err(|_| anyhow!("log.level {} is not one of off error warn info debug trace", self.log.level))
+    }
+
+    pub fn validate(&self)-> Result<()> {
+        self.store_config().context("store")?;
+        self.log_level()?;
+        if let (Some(wal), StoreConfig::Sled(path)) = (&self.wal, self.store_config()?) {
+            if *wal == path { return Err(anyhow!("wal and the sled store can not share {}", path)); }
+        }
+        let accounts = [("withdraw", &self.accounts.withdraw), ("fund", &self.accounts.fund), ("gas_receive", &self.accounts.gas_receive)];
+        for (i, (name, account)) in accounts.iter().enumerate() {
+            if account.trim().is_empty() { return Err(anyhow!("accounts.{} is empty", name)); }
+            if let Some((other, _)) = accounts[..i].iter().find(|(_, a)| a == account ) { return Err(anyhow!("accounts.{} and accounts.{} are both {}", other, name, account)); }
+        }
+        if self.assets.is_empty() { return Err(anyhow!("assets is empty")); }
+        for (i, entry) in self.assets.iter().enumerate() {
+            if entry.name.trim().is_empty() { return Err(anyhow!("assets[{}] has an empty name", i)); }
+            if let Some(j) = self.assets[..i].iter().position(|a| a.name == entry.name ) { return Err(anyhow!("assets[{}] and assets[{}] are both {}", j, i, entry.name)); }
+        }
+        for (name, id) in [("btc", self.system_assets.btc), ("rna", self.system_assets.rna), ("jerry", self.system_assets.jerry)] {
+            if id as usize >= self.assets.len() { return Err(anyhow!("system_assets.{} = {} but only {} assets are configured", name, id, self.assets.len())); }
+        }
+        if self.center_id > Constants::MAX_DATA_CENTER_ID { return Err(anyhow!("center_id {} is greater than {}", self.center_id, Constants::MAX_DATA_CENTER_ID)); }
+        if self.worker_id > Constants::MAX_WORKER_ID { return Err(anyhow!("worker_id {} is greater than {}", self.worker_id, Constants::MAX_WORKER_ID)); }
+        if self.expiry.interval == 0 { return Err(anyhow!("expiry.interval must be greater than 0")); }
+        for (i, rule) in self.expiry.rules.iter().enumerate() {
+            if rule.timeout == 0 { return Err(anyhow!("expiry.rules[{}].timeout must be greater than 0", i)); }
+            if rule.asset.is_some_and(|a| a as usize >= self.assets.len() ) { return Err(anyhow!("expiry.rules[{}].asset {:?} is not configured", i, rule.asset)); }
+        }
+        for (i, rule) in self.fees.iter().enumerate() {
+            rule.validate(self.assets.len()).map_err(|e| anyhow!("fees[{}] {}", i, e))?;
+        }
+        Ok(())
+    }
+}
+
+pub fn load(path: Option<&str>)-> Result<Config> {           //path 为 None 时读取 ACCOUNT_CONFIG 都没有使用默认值 然后使用 ACCOUNT_* 环境变量覆盖
+    let path = path.map(|p| p.to_string() ).or(std::env::var("ACCOUNT_CONFIG").ok());
+    let mut config = match path {
+        Some(path)=> Config::parse(&path, &std::fs::read_to_string(&path).with_context(|| format!("read config {}", path) )?)?,
+        None=> Config::default(),
+    };
+    for key in ENV_OVERRIDES {
+        if let Ok(value) = std::env::var(key) { config.override_with(key, value)?; }
+    }
+    config.validate()?;
+    Ok(config)
+}
+
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+pub fn get()-> &'static Config {                //没有 init 时使用默认配置 和之前的常量一致
+    CONFIG.get_or_init(Config::default)
+}
+
+fn init_logging(config: &Config)-> Result<()> {
+    let dispatch = fern::Dispatch::new().format(|out, message, record| out.finish(format_args!("{} {} {} {}", chrono::Local::now().format("%Y-%m-%d %H:%M:%S"), record.level(), record.target(), message)) )
+        .level(config.log_level()?);
+    let dispatch = match &config.log.file {
+        Some(file)=> dispatch.chain(fern::log_file(file).with_context(|| format!("open log file {}", file) )?),
+        None=> dispatch.chain(std::io::stderr()),
+    };
+    dispatch.apply().map_err(|e| anyhow!("logger {}", e))
+}
+
+pub fn init(config: Config)-> Result<()> {      //必须在访问任何资产和交易之前调用 只能调用一次
+    config.validate()?;
+    store::configure(config.store_config()?)?;
+    init_logging(&config)?;
+    CONFIG.set(config).map_err(|_| anyhow!("config already initialized"))?;
+    let config = get();
+    id::set_worker_id(config.center_id, config.worker_id)?;          //第一次生成 id 之前设置
+    asset::verify_stored()?;
+    let stored = asset::list_assets();
+    for (i, (stored, entry)) in stored.iter().zip(config.assets.iter()).enumerate() {
+        if stored.name != entry.name { return Err(anyhow!("asset {} is {} in the store but {} in the config", i, stored.name, entry.name)); }
+    }
+    for entry in config.assets.iter().skip(stored.len()) {
+        asset::add_asset(entry.name.clone().into(), entry.decimals)?;
+    }
+    Ok(())
+}
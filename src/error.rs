@@ -0,0 +1,67 @@
+use super::trade::StaticStr;
+use super::state::TransitionError;
+
+#[derive(Clone, Debug)]
+pub enum LedgerError {
+    InsufficientBalance{asset: u32, needed: u64, available: u64},
+    InsufficientGas{asset: u32, needed: u64, available: u64},
+    DuplicateTrade(StaticStr),
+    DuplicateHash{hash: StaticStr, asset: u32, trade_id: StaticStr},       //hash 已经属于另一笔充值
+    UnknownTrade(StaticStr),
+    UnknownAccount(StaticStr),
+    InvalidTransition(TransitionError),
+    UnknownAsset(StaticStr),                    //资产名称或者 id
+    RetiredAsset(StaticStr),
+    DuplicateAsset(StaticStr),
+    Overflow{asset: u32},
+    Underflow{asset: u32},                      //锁定余额不足以 confirm 或者 rollback
+    StorageFailure(String),
+    IdGeneration(String),
+    InvalidCursor(StaticStr),
+    EmptyBatch(StaticStr),                      //BatchPay 没有接收方
+    UnknownTransaction(StaticStr),
+    InvalidTransaction(String),
+    InTransaction{trade_id: StaticStr, transaction: StaticStr},        //交易属于未完成的事务 不能单独完成
+}
+
+pub type LedgerResult<T> = std::result::Result<T, LedgerError>;
+
+impl std::fmt::Display for LedgerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>)-> std::fmt::Result {
+        match self {
+            Self::InsufficientBalance{asset, needed, available}=> write!(f, "asset {} need {} but available {}", asset, needed, available),
+            Self::InsufficientGas{asset, needed, available}=> write!(f, "gas asset {} need {} but available {}", asset, needed, available),
+            Self::DuplicateTrade(id)=> write!(f, "trade {} existed", id),
+            Self::DuplicateHash{hash, asset, trade_id}=> write!(f, "hash {} used by trade {} of asset {}", hash, trade_id, asset),
+            Self::UnknownTrade(id)=> write!(f, "unknow trade {}", id),
+            Self::UnknownAccount(account)=> write!(f, "unknow account {}", account),
+            Self::InvalidTransition(e)=> e.fmt(f),
+            Self::UnknownAsset(asset)=> write!(f, "unknow asset {}", asset),
+            Self::RetiredAsset(asset)=> write!(f, "asset {} retired", asset),
+            Self::DuplicateAsset(asset)=> write!(f, "asset {} existed", asset),
+            Self::Overflow{asset}=> write!(f, "asset {} amount overflow", asset),
+            Self::Underflow{asset}=> write!(f, "asset {} amount underflow", asset),
+            Self::StorageFailure(e)=> write!(f, "storage failure {}", e),
+            Self::IdGeneration(e)=> write!(f, "id generation {}", e),
+            Self::InvalidCursor(cursor)=> write!(f, "invalid cursor {}", cursor),
+            Self::EmptyBatch(id)=> write!(f, "batch trade {} has no outputs", id),
+            Self::UnknownTransaction(id)=> write!(f, "unknow transaction {}", id),
+            Self::InvalidTransaction(e)=> write!(f, "invalid transaction {}", e),
+            Self::InTransaction{trade_id, transaction}=> write!(f, "trade {} belongs to transaction {}", trade_id, transaction),
+        }
+    }
+}
+
+impl std::error::Error for LedgerError {}
+
+impl From<TransitionError> for LedgerError {
+    fn from(e: TransitionError)-> Self {
+        Self::InvalidTransition(e)
+    }
+}
+
+impl From<rmp_serde::encode::Error> for LedgerError {
+    fn from(e: rmp_serde::encode::Error)-> Self {
+        Self::StorageFailure(e.to_string())
+    }
+}
@@ -0,0 +1,82 @@
+use once_cell::sync::Lazy;
+use tokio::sync::broadcast;
+use super::trade::{StaticStr, Trade, TransferStatus};
+
+const CAPACITY: usize = 4096;                   //订阅者落后超过这个数量会收到 Lagged
+
+#[derive(Clone, Debug)]
+pub enum LedgerEvent {
+    TradeCreated{asset: u32, trade_id: StaticStr, trade: Trade},
+    StatusChanged{asset: u32, trade_id: StaticStr, from: TransferStatus, trade: Trade},
+    BalanceChanged{account: StaticStr, asset: u32, before: (u64, u64), after: (u64, u64)},     //(可用, 锁定)
+}
+
+impl LedgerEvent {
+    pub fn asset(&self)-> u32 {
+        match self {
+            Self::TradeCreated{asset, ..} | Self::StatusChanged{asset, ..} | Self::BalanceChanged{asset, ..}=> *asset,
+        }
+    }
+    pub fn involves(&self, account: &str)-> bool {
+        match self {
+            Self::TradeCreated{trade, ..} | Self::StatusChanged{trade, ..}=> trade.involves(account) || trade.gas.iter().any(|g| g.to == account ),
+            Self::BalanceChanged{account: a, ..}=> a == account,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct EventFilter {
+    pub account: Option<StaticStr>,
+    pub asset: Option<u32>,
+}
+
+impl EventFilter {
+    fn matches(&self, event: &LedgerEvent)-> bool {
+        self.asset.is_none_or(|asset| asset == event.asset() ) && self.account.as_ref().is_none_or(|account| event.involves(account) )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EventError {
+    Lagged(u64),                                //错过的事件数量 之后可以继续 recv
+    Closed,
+}
+
+static EVENTS: Lazy<broadcast::Sender<LedgerEvent>> = Lazy::new(|| broadcast::channel(CAPACITY).0 );
+
+pub(crate) fn emit(event: LedgerEvent) {
+    let _ = EVENTS.send(event);                 //没有订阅者时忽略
+}
+
+pub(crate) fn emit_balance(account: &StaticStr, before: &[(u64, u64)], after: &[(u64, u64)]) {
+    if EVENTS.receiver_count() == 0 { return }
+    for (asset, after) in after.iter().enumerate() {
+        let before = before.get(asset).cloned().unwrap_or((0, 0));
+        if before != *after {
+            emit(LedgerEvent::BalanceChanged{account: account.clone(), asset: asset as u32, before, after: *after});
+        }
+    }
+}
+
+pub struct Subscription {
+    rx: broadcast::Receiver<LedgerEvent>,
+    filter: EventFilter,
+}
+
+impl Subscription {
+    pub async fn recv(&mut self)-> Result<LedgerEvent, EventError> {
+        loop {
+            match self.rx.recv().await {
+                Ok(event) if self.filter.matches(&event)=> return Ok(event),
+                Ok(_)=> continue,
+                Err(broadcast::error::RecvError::Lagged(n))=> return Err(EventError::Lagged(n)),
+                Err(broadcast::error::RecvError::Closed)=> return Err(EventError::Closed),
+            }
+        }
+    }
+}
+
+pub fn subscribe(filter: EventFilter)-> Subscription {
+    Subscription{rx: EVENTS.subscribe(), filter}
+}
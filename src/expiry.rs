@@ -0,0 +1,100 @@
+use std::borrow::Cow;
+use once_cell::sync::Lazy;
+use scc::HashSet;
+use serde::{Deserialize, Serialize};
+use super::trade::{self, StaticStr, TransferType, TransferStatus};
+use super::store::{meta_write, BACKEND};
+use super::{config, transaction};
+
+const EXPIRY_KEY: &str = "@expiry";
+pub const OPERATOR: &str = "expiry";            //自动失败的交易 review 中的 operator
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExpiryAction {
+    Fail,                                       //失败并且回滚锁定的资金
+    Flag,                                       //只记录 等待人工处理
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ExpiryRecord {
+    pub asset: u32,
+    pub trade_id: StaticStr,
+    pub r#type: TransferType,
+    pub action: ExpiryAction,
+    pub reason: StaticStr,
+    pub tick: i64,
+}
+
+static FLAGGED: Lazy<HashSet<(u32, StaticStr)>> = Lazy::new(|| {        //已经标记过的交易 不重复记录 重启之后从已经保存的记录恢复
+    let flagged = HashSet::default();
+    for r in list_expiry().into_iter().filter(|r| r.action == ExpiryAction::Flag ) {
+        let _ = flagged.insert((r.asset, r.trade_id));
+    }
+    flagged
+});
+
+pub fn timeout(asset: u32, r#type: &TransferType)-> Option<u64> {      //asset 和 type 都匹配的规则优先 其次是只匹配一个的
+    config::get().expiry.rules.iter().filter(|r| r.asset.is_none_or(|a| a == asset ) && r.r#type.as_ref().is_none_or(|t| t == r#type ) )
+        .max_by_key(|r| r.asset.is_some() as u8 + r.r#type.is_some() as u8 ).map(|r| r.timeout )
+}
+
+async fn record(record: &ExpiryRecord) {
+    let buf = rmp_serde::to_vec(record).unwrap();
+    if !meta_write(move |meta| meta.push(EXPIRY_KEY, &buf) ).await {
+        log::error!("expiry {:?} not stored", record);
+    }
+}
+
+pub fn list_expiry()-> Vec<ExpiryRecord> {
+    BACKEND.meta().list(EXPIRY_KEY).iter().filter_map(|buf| rmp_serde::from_slice(buf).ok() ).collect()
+}
+
+pub async fn check_expired()-> Vec<ExpiryRecord> {          //检查一次 返回这次处理的交易
+    let now = chrono::Utc::now().timestamp();
+    let action = config::get().expiry.action;
+    let mut records = Vec::new();
+    for (asset, manager) in trade::managers().into_iter().enumerate() {
+        let asset = asset as u32;
+        let mut expired = Vec::new();
+        manager.trades.scan_async(|id, trade| {
+            if trade.status != TransferStatus::Pending || !matches!(trade.r#type, TransferType::Pay | TransferType::BatchPay | TransferType::Withdraw) { return }
+            if transaction::owner(asset, id).is_some() { return }          //事务中的交易只能一起 abort
+            let since = if trade.update_tick > 0 { trade.update_tick } else { trade.create_tick };
+            if let Some(timeout) = timeout(asset, &trade.r#type) {
+                if now - since >= timeout as i64 { expired.push((id.clone(), trade.r#type.clone(), now - since, timeout)); }
+            }
+        }).await;
+        for (trade_id, r#type, age, timeout) in expired {
+            let reason = Cow::from(format!("pending for {}s, timeout {}s", age, timeout));
+            let done = match action {
+                ExpiryAction::Fail=> super::expire(asset, trade_id.clone(), reason.clone()).await.map_err(|e| log::warn!("expire {} {:?}", trade_id, e) ).is_ok(),
+                ExpiryAction::Flag=> FLAGGED.insert_async((asset, trade_id.clone())).await.is_ok(),
+            };
+            if done {
+                let r = ExpiryRecord{asset, trade_id, r#type, action, reason, tick: now};
+                log::warn!("{:?}", r);
+                record(&r).await;
+                records.push(r);
+            }
+        }
+    }
+    records
+}
+
+pub fn spawn_expiry(mut shutdown: tokio::sync::watch::Receiver<bool>)-> Option<tokio::task::JoinHandle<()>> {     //没有配置规则时不启动 需要在 tokio runtime 中调用
+    let expiry = &config::get().expiry;
+    if expiry.rules.is_empty() { return None }
+    Lazy::force(&FLAGGED);                      //在启动时读取 store 不放在检查中
+    let interval = std::time::Duration::from_secs(expiry.interval);
+    Some(tokio::spawn(async move {
+        let mut timer = tokio::time::interval(interval);
+        loop {
+            tokio::select! {                    //只在两次检查之间退出 不能中断 expire 否则交易失败但资金没有解锁
+                _ = timer.tick()=> {}
+                _ = shutdown.changed()=> break,
+            }
+            check_expired().await;
+        }
+    }))
+}
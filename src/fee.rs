@@ -0,0 +1,140 @@
+use std::borrow::Cow;
+use serde::{Deserialize, Serialize};
+use super::trade::{GasInfo, TransferType, GAS_RECEIVE_ADDR};
+use super::error::{LedgerError, LedgerResult};
+use super::{asset, config};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum FeeSchedule {
+    Flat{amount: u64},
+    Percent{bps: u64},                          //万分之几 30 就是 0.3%
+    Tiered{tiers: Vec<Tier>},                   //按交易金额选择 above 不超过金额的最大一档
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Tier {
+    pub above: u64,
+    #[serde(default)]
+    pub flat: u64,
+    #[serde(default)]
+    pub bps: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeeRule {
+    #[serde(default)]
+    pub asset: Option<u32>,                     //None 匹配所有资产
+    #[serde(default)]
+    pub r#type: Option<TransferType>,           //None 匹配所有类型
+    pub schedule: FeeSchedule,
+    #[serde(default)]
+    pub min: Option<u64>,
+    #[serde(default)]
+    pub max: Option<u64>,
+    #[serde(default)]
+    pub pay_asset: Option<u32>,                 //None 使用交易的资产支付
+    #[serde(default)]
+    pub to: Option<String>,                     //None 使用 GAS_RECEIVE_ADDR
+}
+
+fn percent(amount: u64, bps: u64)-> u128 {
+    amount as u128 * bps as u128 / 10000
+}
+
+impl FeeRule {
+    fn matches(&self, asset: u32, r#type: &TransferType)-> bool {
+        self.asset.is_none_or(|a| a == asset ) && self.r#type.as_ref().is_none_or(|t| t == r#type )
+    }
+
+    pub fn fee(&self, amount: u64)-> u128 {     //没有 min max 限制之前的金额
+        let fee = match &self.schedule {
+            FeeSchedule::Flat{amount}=> *amount as u128,
+            FeeSchedule::Percent{bps}=> percent(amount, *bps),
+            FeeSchedule::Tiered{tiers}=> tiers.iter().filter(|t| t.above <= amount ).max_by_key(|t| t.above ).map(|t| t.flat as u128 + percent(amount, t.bps) ).unwrap_or(0),
+        };
+        fee.max(self.min.unwrap_or(0) as u128).min(self.max.map(|m| m as u128 ).unwrap_or(u128::MAX))
+    }
+
+    pub fn validate(&self, assets: usize)-> Result<(), String> {
+        if self.asset.is_some_and(|a| a as usize >= assets ) { return Err(format!("asset {:?} is not configured", self.asset)); }
+        if self.pay_asset.is_some_and(|a| a as usize >= assets ) { return Err(format!("pay_asset {:?} is not configured", self.pay_asset)); }
+        if let (Some(min), Some(max)) = (self.min, self.max) {
+            if min > max { return Err(format!("min {} is greater than max {}", min, max)); }
+        }
+        match &self.schedule {
+            FeeSchedule::Percent{bps} if *bps > 10000=> Err(format!("bps {} is greater than 10000", bps)),
+            FeeSchedule::Tiered{tiers} if tiers.is_empty()=> Err("tiers is empty".to_string()),
+            FeeSchedule::Tiered{tiers} if tiers.iter().any(|t| t.bps > 10000 )=> Err("tier bps is greater than 10000".to_string()),
+            _=> Ok(()),
+        }
+    }
+}
+
+pub fn rule(asset: u32, r#type: &TransferType)-> Option<&'static FeeRule> {       //asset 和 type 都匹配的规则优先 相同时使用先配置的
+    let rules = &config::get().fees;
+    rules.iter().enumerate().filter(|(_, r)| r.matches(asset, r#type) )
+        .max_by_key(|(i, r)| (r.asset.is_some() as u8 + r.r#type.is_some() as u8, std::cmp::Reverse(*i)) ).map(|(_, r)| r )
+}
+
+pub fn quote_fee(asset: u32, r#type: &TransferType, amount: u64)-> LedgerResult<Vec<GasInfo>> {       //没有规则或者手续费为 0 返回空
+    asset::check_active(asset)?;
+    let Some(rule) = rule(asset, r#type) else { return Ok(Vec::new()) };
+    let pay_asset = rule.pay_asset.unwrap_or(asset);
+    asset::check_active(pay_asset)?;
+    let fee = u64::try_from(rule.fee(amount)).map_err(|_| LedgerError::Overflow{asset: pay_asset})?;
+    if fee == 0 { return Ok(Vec::new()) }
+    let to = rule.to.clone().map(Cow::from).unwrap_or(GAS_RECEIVE_ADDR.clone());
+    Ok(vec![GasInfo::new(pay_asset, fee, to)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(schedule: FeeSchedule, min: Option<u64>, max: Option<u64>)-> FeeRule {
+        FeeRule{asset: None, r#type: None, schedule, min, max, pay_asset: None, to: None}
+    }
+
+    fn tiered()-> FeeSchedule {
+        FeeSchedule::Tiered{tiers: vec![Tier{above: 1000, flat: 5, bps: 10}, Tier{above: 0, flat: 1, bps: 0}, Tier{above: 100000, flat: 0, bps: 5}]}
+    }
+
+    #[test]
+    fn flat_and_percent() {
+        assert_eq!(rule(FeeSchedule::Flat{amount: 7}, None, None).fee(1_000_000), 7);
+        assert_eq!(rule(FeeSchedule::Percent{bps: 30}, None, None).fee(10000), 30);
+        assert_eq!(rule(FeeSchedule::Percent{bps: 30}, None, None).fee(333), 0);           //向下取整
+        assert_eq!(rule(FeeSchedule::Percent{bps: 10000}, None, None).fee(u64::MAX), u64::MAX as u128);
+    }
+
+    #[test]
+    fn tiers_use_the_highest_reached_above() {
+        let r = rule(tiered(), None, None);
+        assert_eq!(r.fee(0), 1);
+        assert_eq!(r.fee(999), 1);
+        assert_eq!(r.fee(1000), 5 + 1);
+        assert_eq!(r.fee(50000), 5 + 50);
+        assert_eq!(r.fee(100000), 50);
+        let r = rule(FeeSchedule::Tiered{tiers: vec![Tier{above: 10, flat: 3, bps: 0}]}, None, None);
+        assert_eq!(r.fee(9), 0);                                        //低于所有档位不收费
+    }
+
+    #[test]
+    fn min_and_max_clamp_the_fee() {
+        assert_eq!(rule(FeeSchedule::Percent{bps: 100}, Some(20), Some(500)).fee(100), 20);
+        assert_eq!(rule(FeeSchedule::Percent{bps: 100}, Some(20), Some(500)).fee(10000), 100);
+        assert_eq!(rule(FeeSchedule::Percent{bps: 100}, Some(20), Some(500)).fee(1_000_000), 500);
+        assert_eq!(rule(tiered(), Some(3), None).fee(0), 3);
+    }
+
+    #[test]
+    fn validate_rejects_bad_rules() {
+        assert!(rule(FeeSchedule::Percent{bps: 10001}, None, None).validate(7).is_err());
+        assert!(rule(FeeSchedule::Tiered{tiers: Vec::new()}, None, None).validate(7).is_err());
+        assert!(rule(FeeSchedule::Flat{amount: 1}, Some(5), Some(4)).validate(7).is_err());
+        assert!(FeeRule{pay_asset: Some(7), ..rule(FeeSchedule::Flat{amount: 1}, None, None)}.validate(7).is_err());
+        assert!(rule(tiered(), Some(1), Some(100)).validate(7).is_ok());
+    }
+}
@@ -0,0 +1,31 @@
+use std::borrow::Cow;
+use once_cell::sync::{Lazy, OnceCell};
+use snowflaker::generator::{Constants, Generator, SnowflakeGenerator};
+use super::trade::StaticStr;
+use super::error::{LedgerError, LedgerResult};
+
+static WORKER: OnceCell<(u64, u64)> = OnceCell::new();
+static GENERATOR: Lazy<LedgerResult<SnowflakeGenerator>> = Lazy::new(|| {
+    let (center, worker) = *WORKER.get_or_init(|| (Constants::DEFAULT_DATA_CENTER_ID, Constants::DEFAULT_WORKER_ID) );
+    SnowflakeGenerator::new(center, worker).map_err(|e| LedgerError::IdGeneration(e.to_string()) )
+});
+
+pub fn set_worker_id(center_id: u64, worker_id: u64)-> LedgerResult<()> {      //多个进程同时生成 id 的时候 worker id 必须不同 需要在第一次生成之前调用
+    if center_id > Constants::MAX_DATA_CENTER_ID || worker_id > Constants::MAX_WORKER_ID {
+        return Err(LedgerError::IdGeneration(format!("worker {}-{} out of range", center_id, worker_id)));
+    }
+    WORKER.set((center_id, worker_id)).map_err(|_| LedgerError::IdGeneration("worker id already set".to_string()) )
+}
+
+pub fn next_trade_id()-> LedgerResult<StaticStr> {
+    let generator = GENERATOR.as_ref().map_err(|e| e.clone() )?;
+    generator.next_id().map(|id| Cow::from(id.to_string()) ).map_err(|e| LedgerError::IdGeneration(e.to_string()) )
+}
+
+pub fn id_timestamp(id: &str)-> Option<i64> {           //id 中包含的毫秒时间戳 兼容 addr.txt 中带引号的格式
+    id.trim().trim_matches('"').parse::<u64>().ok().map(|id| ((id >> Constants::TIMESTAMP_SHIFT) + Constants::EPOCH) as i64 )
+}
+
+pub fn id_datetime(id: &str)-> Option<chrono::DateTime<chrono::Utc>> {
+    id_timestamp(id).and_then(chrono::DateTime::from_timestamp_millis)
+}
@@ -1,9 +1,9 @@
-use crate::trade::{self, GasInfo, ASSET_RNA};
+use crate::trade::{self, GasInfo};
 
-use super::trade::{TransferType, TransferStatus, Trade, StaticStr, TRADES};
+use super::trade::{TransferType, TransferStatus, Trade, StaticStr};
 
-const STATUSS: [(&'static str, TransferStatus); 5] = [("Approving", TransferStatus::Approving), ("WaitBroadcast", TransferStatus::WaitBroadcast), ("Pending", TransferStatus::Pending), ("Succeeded", TransferStatus::Succeeded), ("Failed", TransferStatus::Failed)];
-const TYPES: [(&'static str, TransferType); 6] = [("NodeFund", TransferType::NodeFund), ("Fund", TransferType::Fund), ("Withdraw", TransferType::Withdraw), ("NodeWithdraw", TransferType::NodeWithdraw), ("Pay", TransferType::Pay), ("Gas", TransferType::Gas)];
+const STATUSS: [(&str, TransferStatus); 5] = [("Approving", TransferStatus::Approving), ("WaitBroadcast", TransferStatus::WaitBroadcast), ("Pending", TransferStatus::Pending), ("Succeeded", TransferStatus::Succeeded), ("Failed", TransferStatus::Failed)];
+const TYPES: [(&str, TransferType); 6] = [("NodeFund", TransferType::NodeFund), ("Fund", TransferType::Fund), ("Withdraw", TransferType::Withdraw), ("NodeWithdraw", TransferType::NodeWithdraw), ("Pay", TransferType::Pay), ("Gas", TransferType::Gas)];
 
 pub fn get_status(key: &str)-> Option<TransferStatus> {
     STATUSS.iter().find(|s| s.0 == key ).map(|s| s.1.clone() )
@@ -15,96 +15,211 @@ pub fn get_type(key: &str)-> Option<TransferType> {
 
 use anyhow::{anyhow, Result};
 use chrono::NaiveDateTime;
+use mysql::prelude::{FromValue, Queryable};
 use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet};
 
-pub fn import_trade(asset: u32, trade_id: StaticStr, trade: Trade)-> bool {
-    if !TRADES[asset as usize].store.contains(&trade_id) {
-        TRADES[asset as usize].store.insert(&trade_id, &trade);
-        true
-    } else { false }
-}
-
-pub fn load_air_drop(row: mysql::Row)-> Result<bool> {
-    let id = row.get::<u64, &str>("id").ok_or(anyhow!("no id"))?;
-    let address = Cow::from(row.get::<String, &str>("address").ok_or(anyhow!("no address"))?);
-    let number = row.get::<u64, &str>("had_drop_number").ok_or(anyhow!("no had_drop_number"))?;
-    let trade = Trade::airdrop(address.clone(), number);
-    let _= import_trade(trade::ASSET_JERRY, Cow::from(format!("air_drop_jerry-{}", id)), trade);
-    let gas = row.get::<u64, &str>("had_drop_gas_number").ok_or(anyhow!("no had_drop_gas_number"))?;
-    let trade = Trade::airdrop(address, gas);
-    Ok(import_trade(trade::ASSET_RNA, Cow::from(format!("air_drop_rna-{}", id)), trade))
-}
-
-pub fn load_mysql_row(row: mysql::Row)-> Result<bool> {
-    let tid = row.get::<String, &str>("transfer_id").ok_or(anyhow!("no transfer_id"))?.trim().to_string();
-    let asset = row.get::<String, &str>("transfer_asset_id").ok_or(anyhow!("no asset_id")).and_then(|asset_name| super::get_asset_id(asset_name.trim()) )?;
-    let created = row.get::<String, &str>("created_at").and_then(|dt| NaiveDateTime::parse_from_str(&dt, "%Y-%m-%d %H:%M:%S").ok() ).map(|dt| dt.and_utc().timestamp() ).unwrap_or(0);
-    let updated = row.get::<String, &str>("updated_at").and_then(|dt| NaiveDateTime::parse_from_str(&dt, "%Y-%m-%d %H:%M:%S").ok() ).map(|dt| dt.and_utc().timestamp() ).unwrap_or(0);
-    let status = row.get::<String, &str>("transfer_status").and_then(|t| get_status(&t) ).ok_or(anyhow!("unknow status"))?;
-    let amount = row.get::<u64, &str>("transfer_amount").ok_or(anyhow!("no transfer_amount"))?;
-    let hash = row.get::<Option<String>, &str>("transfer_hash").unwrap_or(Some(String::new())).unwrap_or(String::new());
-    if let Some(transfer_type) = row.get::<String, &str>("transfer_type").and_then(|t| get_type(&t) ) {
-        match transfer_type {
-            TransferType::Fund=> {
-                let from = row.get::<String, &str>("from_address").ok_or(anyhow!("no from_address"))?.trim().to_string();      //这个是存入的地址 我的天啊!@!!@!!
-                let to = row.get::<String, &str>("to_address").ok_or(anyhow!("no to_address"))?.trim().to_string();            //这个没有使用
-                let mut trade = Trade::fund(Cow::from(to), Cow::from(from), amount, Vec::new(), Cow::from(hash));
-                trade.update_tick = updated;
-                trade.create_tick = created;
-                trade.status = status;
-                if import_trade(asset as u32, Cow::from(tid.clone()), trade) {
-                    return Ok(true);
-                }
-            }
-            TransferType::Pay=> {
-                let from = row.get::<String, &str>("from_address").ok_or(anyhow!("no from_address"))?.trim().to_string();
-                let to = row.get::<String, &str>("to_address").ok_or(anyhow!("no to_address"))?.trim().to_string();
-                let mut trade = Trade::pay(Cow::from(from), Cow::from(to), amount, Vec::new(), Cow::from(hash));
-                trade.update_tick = updated;
-                trade.create_tick = created;
-                trade.status = status;
-                if import_trade(asset as u32, Cow::from(tid.clone()), trade) {
-                    return Ok(true);
-                }
+pub fn import_trade(asset: u32, trade_id: StaticStr, trade: Trade)-> bool {      //已经存在的 id 返回 false
+    match trade::manager(asset) {
+        Ok(manager) if !manager.store.contains(&trade_id)=> manager.store.insert(&trade_id, &trade),
+        _=> false
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Table {
+    Transfers,                                  //transfer_id transfer_type ... 的转账记录
+    AirDrop,                                    //每一行生成 JERRY 和 RNA 两笔空投
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ImportCount {
+    pub imported: usize,
+    pub existing: usize,                        //id 已经存在 跳过
+    pub merged_gas: usize,                      //_RNA 手续费合并到对应的 Pay 订单
+    pub rejected: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct Quarantined {
+    pub row: String,                            //column=value 形式的原始数据
+    pub reason: String,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ImportReport {
+    pub counts: BTreeMap<(Option<u32>, Option<TransferType>), ImportCount>,     //无法解析资产或者类型的行记为 None
+    pub quarantine: Vec<Quarantined>,
+}
+
+impl ImportReport {
+    fn count(&mut self, asset: Option<u32>, r#type: Option<TransferType>)-> &mut ImportCount {
+        self.counts.entry((asset, r#type)).or_default()
+    }
+    pub fn total(&self)-> ImportCount {
+        self.counts.values().fold(ImportCount::default(), |t, c| ImportCount{imported: t.imported + c.imported, existing: t.existing + c.existing,
+            merged_gas: t.merged_gas + c.merged_gas, rejected: t.rejected + c.rejected})
+    }
+}
+
+impl std::fmt::Display for ImportReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>)-> std::fmt::Result {
+        writeln!(f, "asset\ttype\timported\texisting\tmerged_gas\trejected")?;
+        for ((asset, r#type), c) in self.counts.iter() {
+            let asset = asset.map(|a| a.to_string() ).unwrap_or("-".to_string());
+            let r#type = r#type.as_ref().map(|t| format!("{:?}", t) ).unwrap_or("-".to_string());
+            writeln!(f, "{}\t{}\t{}\t{}\t{}\t{}", asset, r#type, c.imported, c.existing, c.merged_gas, c.rejected)?;
+        }
+        let t = self.total();
+        write!(f, "total\t\t{}\t{}\t{}\t{}", t.imported, t.existing, t.merged_gas, t.rejected)
+    }
+}
+
+enum Outcome {
+    Imported,
+    Existing,
+    MergedGas,
+}
+
+struct Rejected {
+    asset: Option<u32>,
+    r#type: Option<TransferType>,
+    reason: String,
+}
+
+fn reject(asset: Option<u32>, r#type: Option<TransferType>)-> impl Fn(anyhow::Error)-> Rejected {
+    move |e| Rejected{asset, r#type: r#type.clone(), reason: e.to_string()}
+}
+
+fn column<T: FromValue>(row: &mysql::Row, name: &str)-> Result<T> {       //row.get 类型不对时会 panic
+    match row.get_opt::<T, &str>(name) {
+        Some(Ok(value))=> Ok(value),
+        Some(Err(e))=> Err(anyhow!("bad {} {:?}", name, e)),
+        None=> Err(anyhow!("no {}", name)),
+    }
+}
+
+fn tick(row: &mysql::Row, name: &str)-> i64 {
+    column::<String>(row, name).ok().and_then(|dt| NaiveDateTime::parse_from_str(&dt, "%Y-%m-%d %H:%M:%S").ok() ).map(|dt| dt.and_utc().timestamp() ).unwrap_or(0)
+}
+
+fn dump(row: &mysql::Row)-> String {
+    row.columns_ref().iter().enumerate().map(|(i, c)| format!("{}={}", c.name_str(), row.as_ref(i).map(|v| v.as_sql(true) ).unwrap_or_default()) ).collect::<Vec<_>>().join(", ")
+}
+
+pub struct Importer {
+    dry_run: bool,                              //只检查和统计 不写入 store
+    seen: HashSet<StaticStr>,                   //dry run 时记录本次将会导入的 id
+    pub report: ImportReport,
+}
+
+impl Importer {
+    pub fn new(dry_run: bool)-> Self {
+        Self{dry_run, seen: HashSet::new(), report: ImportReport::default()}
+    }
+
+    fn insert(&mut self, asset: u32, trade_id: StaticStr, trade: Trade)-> Result<Outcome> {
+        let manager = trade::manager(asset)?;
+        if manager.store.contains(&trade_id) || self.seen.contains(&trade_id) { return Ok(Outcome::Existing) }
+        if self.dry_run {
+            self.seen.insert(trade_id);
+        } else if !manager.store.insert(&trade_id, &trade) {
+            return Err(anyhow!("store insert failed"));
+        }
+        Ok(Outcome::Imported)
+    }
+
+    fn merge_gas(&mut self, trade_id: &StaticStr, gas: GasInfo)-> Option<Outcome> {        //对应的 Pay 订单不存在返回 None
+        if self.dry_run && self.seen.contains(trade_id) { return Some(Outcome::MergedGas) }
+        for manager in trade::managers() {
+            if let Some(mut trade) = manager.store.get(trade_id) {
+                if trade.gas.iter().any(|g| g.asset == gas.asset && g.amount == gas.amount && g.to == gas.to ) { return Some(Outcome::Existing) }
+                if self.dry_run { return Some(Outcome::MergedGas) }
+                trade.gas.push(gas);
+                return manager.store.update(trade_id, &trade).then_some(Outcome::MergedGas);
             }
+        }
+        None
+    }
+
+    fn transfer(&mut self, row: &mysql::Row)-> Result<(u32, TransferType, Outcome), Rejected> {
+        let r#type = column::<String>(row, "transfer_type").ok().and_then(|t| get_type(t.trim()) );
+        let asset = column::<String>(row, "transfer_asset_id").and_then(|name| Ok(super::get_asset_id(name.trim())? as u32) ).map_err(reject(None, r#type.clone()))?;
+        let r#type = r#type.ok_or(anyhow!("unknow transfer_type")).map_err(reject(Some(asset), None))?;
+        let rejected = reject(Some(asset), Some(r#type.clone()));
+        let tid = Cow::from(column::<String>(row, "transfer_id").map_err(&rejected)?.trim().to_string());
+        let status = column::<String>(row, "transfer_status").ok().and_then(|t| get_status(t.trim()) ).ok_or(anyhow!("unknow status")).map_err(&rejected)?;
+        let amount = column::<u64>(row, "transfer_amount").map_err(&rejected)?;
+        let hash = Cow::from(column::<Option<String>>(row, "transfer_hash").ok().flatten().unwrap_or_default());
+        let from = Cow::from(column::<String>(row, "from_address").map_err(&rejected)?.trim().to_string());
+        let to = Cow::from(column::<String>(row, "to_address").map_err(&rejected)?.trim().to_string());
+        let mut trade = match r#type {
+            TransferType::Fund=> Trade::fund(to, from, amount, Vec::new(), hash),      //from_address 是存入的地址 to_address 没有使用
+            TransferType::Pay=> Trade::pay(from, to, amount, Vec::new(), hash),
+            TransferType::Withdraw=> Trade::withdraw(from, to, amount, Vec::new(), hash),
             TransferType::Gas=> {
-                let from = row.get::<String, &str>("from_address").ok_or(anyhow!("no from_address"))?.trim().to_string();
-                let to = Cow::from(row.get::<String, &str>("to_address").ok_or(anyhow!("no to_address"))?.trim().to_string());
-                if tid.ends_with("_RNA") {          //手续费 RNA的手续费需要合并到 Pay 订单中
+                if tid.ends_with("_RNA") {                      //手续费 RNA的手续费需要合并到 Pay 订单中
                     let trade_id = Cow::from(tid.replace("_RNA", "_0"));
-                    if trade::update_trade(&trade_id, |trade| {
-                        trade.gas.push(GasInfo::new(asset as u32, amount, Cow::from(Cow::from(to.clone()))));
-                    }) {
-                        return Ok(true);
-                    } 
-                }
-                let mut trade = Trade::gas(Cow::from(from), to, amount);
-                trade.update_tick = updated;
-                trade.create_tick = created;
-                trade.status = status;
-                if import_trade(asset as u32, Cow::from(tid.clone()), trade) {
-                    return Ok(true);
+                    if let Some(outcome) = self.merge_gas(&trade_id, GasInfo::new(asset, amount, to.clone())) {
+                        return Ok((asset, r#type, outcome));
+                    }
                 }
+                Trade::gas(from, to, amount)
             }
-            TransferType::Withdraw=> {
-                let from = row.get::<String, &str>("from_address").ok_or(anyhow!("no from_address"))?.trim().to_string();
-                let to = row.get::<String, &str>("to_address").ok_or(anyhow!("no to_address"))?.trim().to_string();
-                let mut trade = Trade::withdraw(Cow::from(from), Cow::from(to), amount, Vec::new(), Cow::from(hash));
-                trade.update_tick = updated;
-                trade.create_tick = created;
-                trade.status = status;
-                if import_trade(asset as u32, Cow::from(tid.clone()), trade) {
-                    return Ok(true);
+            _=> return Err(rejected(anyhow!("unsupported transfer_type {:?}", r#type))),
+        };
+        trade.update_tick = tick(row, "updated_at");
+        trade.create_tick = tick(row, "created_at");
+        trade.status = status;
+        self.insert(asset, tid, trade).map(|outcome| (asset, r#type, outcome) ).map_err(rejected)
+    }
+
+    fn air_drop(&mut self, row: &mysql::Row)-> Result<Vec<(u32, Outcome)>, Rejected> {
+        let rejected = reject(None, Some(TransferType::AirDrop));
+        let id = column::<u64>(row, "id").map_err(&rejected)?;
+        let address = Cow::from(column::<String>(row, "address").map_err(&rejected)?);
+        let number = column::<u64>(row, "had_drop_number").map_err(&rejected)?;
+        let gas = column::<u64>(row, "had_drop_gas_number").map_err(&rejected)?;
+        let mut outcomes = Vec::new();
+        for (asset, prefix, amount) in [(*trade::ASSET_JERRY, "air_drop_jerry", number), (*trade::ASSET_RNA, "air_drop_rna", gas)] {
+            let outcome = self.insert(asset, Cow::from(format!("{}-{}", prefix, id)), Trade::airdrop(address.clone(), amount))
+                .map_err(reject(Some(asset), Some(TransferType::AirDrop)))?;
+            outcomes.push((asset, outcome));
+        }
+        Ok(outcomes)
+    }
+
+    pub fn load_row(&mut self, table: Table, row: &mysql::Row) {          //失败的行放入 quarantine 不中断导入
+        let result = match table {
+            Table::Transfers=> self.transfer(row).map(|(asset, r#type, outcome)| vec![(asset, r#type, outcome)] ),
+            Table::AirDrop=> self.air_drop(row).map(|o| o.into_iter().map(|(asset, outcome)| (asset, TransferType::AirDrop, outcome) ).collect() ),
+        };
+        match result {
+            Ok(outcomes)=> for (asset, r#type, outcome) in outcomes {
+                let count = self.report.count(Some(asset), Some(r#type));
+                match outcome {
+                    Outcome::Imported=> count.imported += 1,
+                    Outcome::Existing=> count.existing += 1,
+                    Outcome::MergedGas=> count.merged_gas += 1,
                 }
             }
-            _=> {
-                panic!("ohh---{:?}", row);
-            }    
+            Err(e)=> {
+                self.report.count(e.asset, e.r#type).rejected += 1;
+                self.report.quarantine.push(Quarantined{row: dump(row), reason: e.reason});
+            }
         }
     }
-    Ok(false)
+}
+
+pub fn import_mysql(url: &str, table_name: &str, table: Table, dry_run: bool)-> Result<ImportReport> {      //逐行读取 不把整张表放进内存
+    let mut conn = mysql::Pool::new(url)?.get_conn()?;
+    let mut importer = Importer::new(dry_run);
+    for row in conn.query_iter(format!("SELECT * FROM {}", table_name))? {
+        importer.load_row(table, &row?);
+    }
+    Ok(importer.report)
 }
 
 pub fn clean_up() {         //清除所有 key 谨慎使用
-    TRADES.iter().for_each(|t| t.store.clean_up() );   
-}
\ No newline at end of file
+    trade::managers().iter().for_each(|t| t.store.clean_up() );
+}
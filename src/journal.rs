@@ -0,0 +1,100 @@
+use serde::{Deserialize, Serialize};
+use super::trade::{StaticStr, Trade, TransferType, TransferStatus, FUND_ADDR};
+use super::store::{meta_write, MetaStore, BACKEND};
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Reason {
+    Lock,
+    Confirm,
+    Rollback,
+    Income,
+    Gas,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub account: StaticStr,
+    pub asset: u32,
+    pub available: i128,                        //可用余额的变化
+    pub locked: i128,                           //锁定余额的变化
+    pub trade_id: StaticStr,
+    pub reason: Reason,
+    pub tick: i64,
+}
+
+impl JournalEntry {
+    fn new(account: &StaticStr, asset: u32, available: i128, locked: i128, trade_id: &StaticStr, reason: Reason)-> Self {
+        Self{account: account.clone(), asset, available, locked, trade_id: trade_id.clone(), reason, tick: chrono::Utc::now().timestamp()}
+    }
+}
+
+pub fn lock(asset: u32, trade_id: &StaticStr, trade: &Trade)-> Vec<JournalEntry> {          //可用转到锁定 每一条自身合计为 0
+    let mut entries = vec![JournalEntry::new(&trade.from, asset, -(trade.amount as i128), trade.amount as i128, trade_id, Reason::Lock)];
+    for g in &trade.gas {
+        entries.push(JournalEntry::new(&trade.from, g.asset, -(g.amount as i128), g.amount as i128, trade_id, Reason::Lock));
+    }
+    entries
+}
+
+pub fn rollback(asset: u32, trade_id: &StaticStr, trade: &Trade)-> Vec<JournalEntry> {
+    let mut entries = vec![JournalEntry::new(&trade.from, asset, trade.amount as i128, -(trade.amount as i128), trade_id, Reason::Rollback)];
+    for g in &trade.gas {
+        entries.push(JournalEntry::new(&trade.from, g.asset, g.amount as i128, -(g.amount as i128), trade_id, Reason::Rollback));
+    }
+    entries
+}
+
+pub fn confirm(asset: u32, trade_id: &StaticStr, trade: &Trade)-> Vec<JournalEntry> {       //锁定的转出 接收方和 gas 接收方入账
+    let mut entries = vec![JournalEntry::new(&trade.from, asset, 0, -(trade.amount as i128), trade_id, Reason::Confirm)];
+    for (to, amount) in trade.credits() {
+        entries.push(JournalEntry::new(&to, asset, amount as i128, 0, trade_id, Reason::Income));
+    }
+    for g in &trade.gas {
+        entries.push(JournalEntry::new(&trade.from, g.asset, 0, -(g.amount as i128), trade_id, Reason::Confirm));
+        entries.push(JournalEntry::new(&g.to, g.asset, g.amount as i128, 0, trade_id, Reason::Gas));
+    }
+    entries
+}
+
+pub fn fund(asset: u32, trade_id: &StaticStr, trade: &Trade)-> Vec<JournalEntry> {          //充值从 FUND_ADDR 转入
+    vec![JournalEntry::new(&FUND_ADDR, asset, -(trade.amount as i128), 0, trade_id, Reason::Income),
+        JournalEntry::new(&trade.to, asset, trade.amount as i128, 0, trade_id, Reason::Income)]
+}
+
+fn key(account: &str)-> String {
+    format!("@journal::{}", account)
+}
+
+pub(crate) fn write(meta: &dyn MetaStore, entries: Vec<JournalEntry>)-> bool {         //同一个操作的分录 按资产合计必须为 0
+    debug_assert!(entries.iter().all(|e| entries.iter().filter(|o| o.asset == e.asset ).map(|o| o.available + o.locked ).sum::<i128>() == 0 ));
+    let mut stored = true;
+    for entry in entries {
+        if !meta.push(&key(&entry.account), &rmp_serde::to_vec(&entry).unwrap()) {
+            log::error!("journal {:?} not stored", entry);
+            stored = false;
+        }
+    }
+    stored
+}
+
+pub async fn record(entries: Vec<JournalEntry>) {        //在 meta 写入线程执行 返回之后 wal 才会删除这个操作
+    meta_write(move |meta| write(meta, entries) ).await;
+}
+
+pub fn get_journal(account: &str)-> Vec<JournalEntry> {
+    BACKEND.meta().list(&key(account)).iter().filter_map(|buf| rmp_serde::from_slice(buf).ok() ).collect()
+}
+
+pub fn effects(asset: u32, trade_id: &StaticStr, trade: &Trade)-> Vec<JournalEntry> {       //交易在当前状态下累计产生的分录 和 load_all 的处理一致
+    match (&trade.r#type, &trade.status) {
+        (TransferType::Fund, TransferStatus::Succeeded)=> fund(asset, trade_id, trade),
+        (TransferType::Pay | TransferType::Gas | TransferType::Withdraw | TransferType::BatchPay, TransferStatus::Succeeded)=> {
+            let mut entries = lock(asset, trade_id, trade);
+            entries.extend(confirm(asset, trade_id, trade));
+            entries
+        }
+        (TransferType::Pay | TransferType::Gas | TransferType::Withdraw | TransferType::BatchPay, TransferStatus::Failed)=> Vec::new(),
+        (TransferType::Pay | TransferType::Gas | TransferType::Withdraw | TransferType::BatchPay, _)=> lock(asset, trade_id, trade),
+        _=> Vec::new()
+    }
+}
@@ -1,264 +1,682 @@
 pub mod trade;
 pub mod import;
-use trade::{GasInfo, StaticStr, Trade, ASSET_RNA, WITHDRAW_ADDR};
+pub mod asset;
+pub mod store;
+pub mod state;
+pub mod journal;
+pub mod reconcile;
+pub mod snapshot;
+pub mod error;
+pub mod id;
+pub mod query;
+pub mod events;
+pub mod config;
+pub mod wal;
+pub mod expiry;
+pub mod fee;
+pub mod transaction;
+pub mod rpc;
+use trade::{GasInfo, StaticStr, Trade};
+use asset::ASSETS;
 use scc::{HashMap, HashSet};
+use std::collections::BTreeSet;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
 pub struct Account {
-    amounts: [(u64, u64); ASSET_NUM],
-    trades: Vec<(u32, StaticStr)>
+    amounts: Vec<(u64, u64)>,                   //下标是 asset id 按需扩展
+    trades: BTreeSet<(i64, u32, StaticStr)>     //(create_tick, asset, trade_id) 有序 分页查询直接定位到 cursor 旧格式的快照不能解析 会全量加载
 }
 
-impl Default for Account {
-    fn default()-> Self {
-        Self{amounts: [(0,0), (0,0), (0,0), (0,0), (0,0), (0,0), (0,0), (0,0)], trades: Vec::new()}
+impl Account {
+    fn reserve(&mut self, asset: usize, trade: &Trade)-> LedgerResult<()> {      //保证 amounts 覆盖交易和 gas 用到的资产 最多扩展到已有的资产数量
+        let max = trade.gas.iter().map(|g| g.asset as usize ).chain(std::iter::once(asset)).max().unwrap_or(0);
+        self.extend(max)
+    }
+    fn extend(&mut self, asset: usize)-> LedgerResult<()> {
+        if asset >= ASSETS.len() { return Err(LedgerError::UnknownAsset(std::borrow::Cow::from(asset.to_string()))); }
+        if self.amounts.len() < ASSETS.len() {
+            self.amounts.resize(ASSETS.len(), (0, 0));
+        }
+        Ok(())
     }
-}
 
-impl Account {
-    pub fn lock(&mut self, asset: usize, trade: &Trade)-> bool {    //锁定资金 开始提现或者转出
-        if trade.gas.iter().find(|g| 
-            if self.amounts[asset].0 < g.amount + if g.asset == asset as u32 { trade.amount } else { 0 }  { true }
-            else { false }
-        ).is_some() { return false }            //存在不够的 gas
-        if self.amounts[asset].0 >= trade.amount {
-            self.amounts[asset].0 -= trade.amount;
-            self.amounts[asset].1 += trade.amount;
-            for g in &trade.gas {
-                self.amounts[g.asset as usize].0 -= g.amount;
-                self.amounts[g.asset as usize].1 += g.amount;
-            }
-            true
-        } else { false }
+    pub fn amount(&self, asset: usize)-> (u64, u64) {
+        self.amounts.get(asset).cloned().unwrap_or((0, 0))
     }
 
-    pub fn confirm(&mut self, asset: usize, trade: &Trade)-> bool {        //确认转出 或者确认提现
-        self.amounts[asset].1 -= trade.amount;
-        for g in &trade.gas {
-            self.amounts[g.asset as usize].1 -= g.amount;
+    fn transact(&mut self, changes: &[(usize, i128, i128)])-> LedgerResult<()> {      //(asset, 可用变化, 锁定变化) 全部成功才修改
+        let mut amounts = self.amounts.clone();
+        for (asset, available, locked) in changes {
+            let amount = &mut amounts[*asset];
+            amount.0 = shift(amount.0, *available, *asset)?;
+            amount.1 = shift(amount.1, *locked, *asset)?;
         }
-        true
+        self.amounts = amounts;
+        Ok(())
     }
-    pub fn rollback(&mut self, asset: usize, trade: &Trade)-> bool {       //用于转账失败或者 提现失败的回滚
-        self.amounts[asset].1 -= trade.amount;
-        self.amounts[asset].0 += trade.amount;
+
+    pub fn lock(&mut self, asset: usize, trade: &Trade)-> LedgerResult<()> {    //锁定资金 开始提现或者转出
+        self.reserve(asset, trade)?;
+        if self.amounts[asset].0 < trade.amount {
+            return Err(LedgerError::InsufficientBalance{asset: asset as u32, needed: trade.amount, available: self.amounts[asset].0});
+        }
+        let mut needed = std::collections::BTreeMap::from([(asset, trade.amount)]);      //同一个资产的金额和多条 gas 合计之后检查
         for g in &trade.gas {
-            self.amounts[g.asset as usize].1 -= g.amount;
-            self.amounts[g.asset as usize].0 += g.amount;
+            let total = needed.entry(g.asset as usize).or_insert(0);
+            *total = total.checked_add(g.amount).ok_or(LedgerError::Overflow{asset: g.asset})?;
         }
-        true
+        for (asset, needed) in needed {
+            if self.amounts[asset].0 < needed {                       //存在不够的 gas
+                return Err(LedgerError::InsufficientGas{asset: asset as u32, needed, available: self.amounts[asset].0});
+            }
+        }
+        self.transact(&changes(asset, trade, -1, 1))
     }
 
-    pub fn income(&mut self, asset: usize, amount: u64)-> bool {        //仅用于充值到账 以及转账接收方到账
-        self.amounts[asset].0 += amount;
-        true
+    pub fn confirm(&mut self, asset: usize, trade: &Trade)-> LedgerResult<()> {        //确认转出 或者确认提现
+        self.reserve(asset, trade)?;
+        self.transact(&changes(asset, trade, 0, -1))
+    }
+    pub fn rollback(&mut self, asset: usize, trade: &Trade)-> LedgerResult<()> {       //用于转账失败或者 提现失败的回滚
+        self.reserve(asset, trade)?;
+        self.transact(&changes(asset, trade, 1, -1))
     }
-    pub fn decrease(&mut self, asset: usize, trade: &Trade)-> Result<()> {      //减少 asset 仅用于重新加载的时候 没有锁定直接减少
+
+    pub fn income(&mut self, asset: usize, amount: u64)-> LedgerResult<()> {        //仅用于充值到账 以及转账接收方到账
+        self.extend(asset)?;
+        self.transact(&[(asset, amount as i128, 0)])
+    }
+    pub fn decrease(&mut self, asset: usize, trade: &Trade)-> LedgerResult<()> {      //减少 asset 仅用于重新加载的时候 没有锁定直接减少
+        self.reserve(asset, trade)?;
         if self.amounts[asset].0 < trade.amount {
-            return Err(anyhow!("asset {} amount {} less than {}", asset, self.amounts[asset].0, trade.amount));
+            return Err(LedgerError::InsufficientBalance{asset: asset as u32, needed: trade.amount, available: self.amounts[asset].0});
         }
-        self.amounts[asset].0 -= trade.amount;
-        for g in &trade.gas {
-            if self.amounts[g.asset as usize].0 < g.amount {
-                return Err(anyhow!("gas asset {} amount {} less than {}", g.asset, self.amounts[g.asset as usize].0, g.amount));
-            } else {
-                self.amounts[g.asset as usize].0 -= g.amount;
-            }
-        }
-       Ok(())
+        self.transact(&changes(asset, trade, -1, 0))
     }
 }
 
+fn changes(asset: usize, trade: &Trade, available: i128, locked: i128)-> Vec<(usize, i128, i128)> {      //交易金额和 gas 按照方向展开
+    std::iter::once((asset, trade.amount)).chain(trade.gas.iter().map(|g| (g.asset as usize, g.amount) ))
+        .map(|(asset, amount)| (asset, available * amount as i128, locked * amount as i128) ).collect()
+}
+
+fn shift(value: u64, delta: i128, asset: usize)-> LedgerResult<u64> {
+    let result = value as i128 + delta;
+    if result < 0 { Err(LedgerError::Underflow{asset: asset as u32}) }
+    else if result > u64::MAX as i128 { Err(LedgerError::Overflow{asset: asset as u32}) }
+    else { Ok(result as u64) }
+}
+
 use once_cell::sync::Lazy;
 use std::sync::Arc;
 static ACCOUNTS: Lazy<Arc<HashMap<StaticStr, Account>>> = Lazy::new(|| Arc::new(HashMap::default()) );
 pub static WARNINGS: Lazy<Arc<HashSet<(u32, StaticStr)>>> = Lazy::new(|| Arc::new(HashSet::default()) );
+static GATE: Lazy<tokio::sync::RwLock<()>> = Lazy::new(|| tokio::sync::RwLock::new(()) );     //修改账户的操作持有读锁 快照持有写锁
 
-async fn account_modify<F: FnOnce(&mut Account)-> bool>(account: &StaticStr, f: F)-> bool {
-    ACCOUNTS.update_async(account, |_, account| f(account) ).await.unwrap_or(false)
+async fn account_modify<F: FnOnce(&mut Account)-> LedgerResult<()>>(account: &StaticStr, f: F)-> LedgerResult<()> {
+    ACCOUNTS.update_async(account, |name, account| {
+        let before = account.amounts.clone();
+        f(account)?;
+        events::emit_balance(name, &before, &account.amounts);
+        Ok(())
+    }).await.unwrap_or(Err(LedgerError::UnknownAccount(account.clone())))
 }
 
-async fn account_add(account: StaticStr, asset: u32, trade_id: StaticStr, amount: Option<u64>) {       //用于转账接收方或者充值方 如果账号不存在则创建一个
+async fn account_add(account: StaticStr, asset: u32, trade_id: StaticStr, tick: i64, amount: Option<u64>) {       //用于转账接收方或者充值方 如果账号不存在则创建一个
     ACCOUNTS.entry_async(account).await.and_modify(|account| {
-        amount.map(|amount| account.amounts[asset as usize].0 += amount );
-        account.trades.push((asset, trade_id.clone()));
-    }).or_insert({
-        let mut account = Account{amounts: [(0,0), (0,0), (0,0), (0,0), (0,0), (0,0), (0,0), (0,0)], trades: vec![(asset, trade_id)]};
-        amount.map(|amount| account.amounts[asset as usize].0 += amount );
+        if let Some(amount) = amount { let _ = account.income(asset as usize, amount); }
+        account.trades.insert((tick, asset, trade_id.clone()));
+    }).or_insert_with(|| {
+        let mut account = Account{amounts: vec![(0, 0); ASSETS.len()], trades: BTreeSet::from([(tick, asset, trade_id)])};
+        if let Some(amount) = amount { let _ = account.income(asset as usize, amount); }
         account
     });
 }
 
-async fn account_start(asset: u32, trade_id: StaticStr, trade: &Trade)-> bool {       //创建一笔转账或者提现交易
-    account_modify(&trade.from, |account| 
-        if account.lock(asset as usize, &trade) {
-            account.trades.push((asset, trade_id));
-            true
-        } else { false }
-    ).await
+async fn account_forget(account: &StaticStr, asset: u32, trade_id: &StaticStr) {       //交易没有保存成功 删除 account_add 和 account_start 记录的 id
+    let _ = ACCOUNTS.update_async(account, |_, account| account.trades.retain(|t| t.1 != asset || t.2 != *trade_id ) ).await;
+}
+
+async fn account_income(account: &StaticStr, asset: u32, amount: u64)-> LedgerResult<()> {        //入账 账户不存在时创建 例如外部提现地址和 WITHDRAW_ADDR
+    let mut entry = ACCOUNTS.entry_async(account.clone()).await.or_insert_with(|| Account{amounts: vec![(0, 0); ASSETS.len()], trades: BTreeSet::new()} );
+    let before = entry.get().amounts.clone();
+    entry.get_mut().income(asset as usize, amount)?;
+    events::emit_balance(account, &before, &entry.get().amounts);
+    Ok(())
 }
 
-async fn account_success(asset: u32, trade: &Trade, with_lock: bool)-> bool {            //成功完成一笔交易
-    if account_modify(&trade.from, |account| {
+async fn account_start(asset: u32, trade_id: StaticStr, trade: &Trade)-> LedgerResult<()> {       //创建一笔转账或者提现交易
+    ACCOUNTS.update_async(&trade.from, |name, account| {
+        let before = account.amounts.clone();
+        account.lock(asset as usize, trade)?;
+        events::emit_balance(name, &before, &account.amounts);
+        account.trades.insert((trade.create_tick, asset, trade_id));
+        Ok(())
+    }).await.unwrap_or(Err(LedgerError::InsufficientBalance{asset, needed: trade.amount, available: 0}))         //账户不存在 没有任何余额
+}
+
+async fn account_success(asset: u32, trade: &Trade, with_lock: bool)-> LedgerResult<()> {            //成功完成一笔交易
+    account_modify(&trade.from, |account| {
         if with_lock {
-            account.confirm(asset as usize, &trade)
+            account.confirm(asset as usize, trade)
         } else {
-            let _ = account.decrease(asset as usize, &trade).map_err(|e| {
+            let _ = account.decrease(asset as usize, trade).map_err(|e| {
                 log::error!("err {:?} {:?}", e, trade);
                 let _ = WARNINGS.insert((asset, trade.from.clone()));
             });
-            true
+            Ok(())
         }
-    }).await {
-        for g in &trade.gas {
-            account_modify(&g.to, |account| account.income(g.asset as usize, g.amount) ).await;
+    }).await?;
+    let mut result = Ok(());                                    //一个入账失败 其他的仍然入账 返回第一个错误
+    for (to, asset, amount) in incomes(asset, trade) {
+        let credited = account_income(&to, asset, amount).await;
+        if result.is_ok() { result = credited; }
+    }
+    result
+}
+
+fn incomes(asset: u32, trade: &Trade)-> Vec<(StaticStr, u32, u64)> {          //成功之后入账的 (账户, 资产, 金额) 包括 gas
+    trade.gas.iter().map(|g| (g.to.clone(), g.asset, g.amount) ).chain(trade.credits().into_iter().map(|(to, amount)| (to, asset, amount) )).collect()
+}
+
+async fn check_completed(trades: &[(u32, &Trade)], success: bool)-> LedgerResult<()> {      //修改状态之前在账户的副本上试做 任何一个余额变化失败都不修改
+    let mut accounts: std::collections::BTreeMap<StaticStr, Account> = std::collections::BTreeMap::new();
+    for (asset, trade) in trades {
+        if !accounts.contains_key(&trade.from) {
+            let account = ACCOUNTS.read_async(&trade.from, |_, account| account.clone() ).await.ok_or(LedgerError::UnknownAccount(trade.from.clone()))?;
+            accounts.insert(trade.from.clone(), account);
         }
-        if trade.r#type == TransferType::Withdraw && trade.from == trade.to {
-            account_modify(&std::borrow::Cow::from(WITHDRAW_ADDR), |account| account.income(asset as usize, trade.amount) ).await
-        } else {    
-            account_modify(&trade.to, |account| account.income(asset as usize, trade.amount) ).await
+        let from = accounts.get_mut(&trade.from).unwrap();
+        if !success { from.rollback(*asset as usize, trade)?; continue }
+        from.confirm(*asset as usize, trade)?;
+        for (to, asset, amount) in incomes(*asset, trade) {
+            if !accounts.contains_key(&to) {
+                let account = ACCOUNTS.read_async(&to, |_, account| account.clone() ).await.unwrap_or_default();      //不存在的接收方入账时创建
+                accounts.insert(to.clone(), account);
+            }
+            accounts.get_mut(&to).unwrap().income(asset as usize, amount)?;
         }
-    } else { false }           
+    }
+    Ok(())
+}
+
+async fn check_finish<F: Fn(&mut Trade)-> Result<(), state::TransitionError>>(manager: &trade::TradeManager, asset: u32, trade_id: &StaticStr, success: bool, f: &F)-> LedgerResult<()> {      //状态可以变化时才试做余额 否则由 update 返回错误
+    let Some(trade) = manager.trade(trade_id).await else { return Ok(()) };
+    if f(&mut trade.clone()).is_err() { return Ok(()) }
+    check_completed(&[(asset, &trade)], success).await
+}
+
+async fn apply_completed(asset: u32, trade_id: &StaticStr, old: &Trade, success: bool) {      //交易状态已经保存 余额的错误只记录 不能再返回失败
+    let result = if success { account_success(asset, old, true).await } else { account_modify(&old.from, |account| account.rollback(asset as usize, old) ).await };
+    if let Err(e) = result {
+        log::error!("trade {} completed {} but balance {:?}", trade_id, success, e);
+        let _ = WARNINGS.insert((asset, old.from.clone()));
+    }
+    journal::record(if success { journal::confirm(asset, trade_id, old) } else { journal::rollback(asset, trade_id, old) }).await;
 }
 
-use anyhow::{Result, anyhow};
-use trade::{ASSET_NAMES, ASSET_NUM, TRADES, TransferType, TransferStatus};
+use error::{LedgerError, LedgerResult};
+use trade::{manager, managers, TransferType, TransferStatus};
+pub use trade::find_by_hash;
+pub use fee::quote_fee;
+pub use transaction::{begin_transaction, commit_transaction, abort_transaction};
 
-pub fn get_asset_id(asset_name: &str)-> Result<usize> {
-    ASSET_NAMES.iter().position(|a| *a == asset_name ).ok_or(anyhow!("unknow asset {}", asset_name) )
+pub fn get_asset_id(asset_name: &str)-> LedgerResult<usize> {
+    ASSETS.position(asset_name).ok_or(LedgerError::UnknownAsset(std::borrow::Cow::from(asset_name.to_string())))
 }
 
-pub async fn get_amount(account: &StaticStr)-> Option<[(u64, u64); ASSET_NUM]>{
-    ACCOUNTS.get_async(account).await.map(|account| account.amounts )
+pub async fn get_amount(account: &StaticStr)-> Option<Vec<(u64, u64)>>{
+    ACCOUNTS.get_async(account).await.map(|account| {
+        let mut amounts = account.amounts.clone();
+        amounts.resize(amounts.len().max(ASSETS.len()), (0, 0));
+        amounts
+    })
 }
 
-pub async fn get_trades(asset: u32, account: &StaticStr, descend: bool)-> Vec<(StaticStr, Trade)>{
-    let ids = ACCOUNTS.get(account).map(|account| {
-        account.trades.iter().filter_map(|t| if t.0 == asset { Some(t.1.clone()) } else { None }).collect()
-    }).unwrap_or(Vec::new());
+pub async fn get_trades(asset: u32, account: &StaticStr, descend: bool)-> LedgerResult<Vec<(StaticStr, Trade)>>{
+    let manager = manager(asset)?;
+    let mut ids: Vec<StaticStr> = ACCOUNTS.get(account).map(|account| {          //已经按照 create_tick 排序
+        account.trades.iter().filter_map(|t| if t.1 == asset { Some(t.2.clone()) } else { None }).collect()
+    }).unwrap_or_default();
+    if descend { ids.reverse(); }
     let mut trades = Vec::new();
     for id in ids {
-        TRADES[asset as usize].trade(&id).await.map(|t| trades.push((id.clone(), t)) );
+        if let Some(t) = manager.trade(&id).await { trades.push((id.clone(), t)) }
     }
-    if descend {
-        trades.sort_by_key(|trade| trade.1.create_tick );
-    }
-    trades
+    Ok(trades)
 }
 
-pub async fn add_fund(asset: u32, trade_id: StaticStr, from: StaticStr, to: StaticStr, amount: u64, gas: Vec<GasInfo>, hash: StaticStr)-> Result<()> {
-    if TRADES[asset as usize].contains(&trade_id).await { return Err(anyhow!("trade {} existed", trade_id )); }
+pub async fn add_fund(asset: u32, trade_id: StaticStr, from: StaticStr, to: StaticStr, amount: u64, gas: Vec<GasInfo>, hash: StaticStr)-> LedgerResult<()> {
+    let _gate = GATE.read().await;
+    asset::check_active(asset)?;
+    check_gas(&gas)?;
+    let manager = manager(asset)?;
+    let _reserved = manager.reserve(&trade_id).await?;
+    let _hash = trade::reserve_fund_hash(&hash, asset, &trade_id).await?;
     let trade = Trade::fund(from, to.clone(), amount, gas, hash);
-    let _ = TRADES[asset as usize].insert(trade_id.clone(), trade.clone()).await;
-    account_add(to, asset, trade_id, None).await;
+    let tick = trade.create_tick;
+    let _pending = wal::begin(wal::Op::Fund{asset, trade_id: trade_id.clone(), trade: trade.clone()}).await?;
+    manager.insert(trade_id.clone(), trade).await?;
+    account_add(to, asset, trade_id, tick, None).await;
     Ok(())
 }
 
-pub async fn complete_fund(asset: u32, trade_id: StaticStr, success: bool)-> bool {
-    if let Some(old) = TRADES[asset as usize].update(trade_id, |mut trade| if trade.success() { Some(trade) } else { None } ).await {      //update success
-        if success { account_modify(&old.to, |account| account.income(asset as usize, old.amount) ).await }
-        else { true }
-    } else { false}
+pub async fn add_fund_auto(asset: u32, from: StaticStr, to: StaticStr, amount: u64, gas: Vec<GasInfo>, hash: StaticStr)-> LedgerResult<StaticStr> {     //使用 snowflake 生成 trade id 并返回
+    let trade_id = id::next_trade_id()?;
+    add_fund(asset, trade_id.clone(), from, to, amount, gas, hash).await.map(|_| trade_id )
 }
 
+pub async fn complete_fund(asset: u32, trade_id: StaticStr, success: bool)-> LedgerResult<()> {       //需要先 mark_broadcast 进入 Pending
+    let _gate = GATE.read().await;
+    let _pending = wal::begin(wal::Op::Complete{asset, trade_id: trade_id.clone(), success}).await?;
+    let old = manager(asset)?.update(&trade_id, |trade| trade.modify(success) ).await?;
+    if success {
+        account_modify(&old.to, |account| account.income(asset as usize, old.amount) ).await?;
+        journal::record(journal::fund(asset, &trade_id, &old)).await;
+    }
+    Ok(())
+}
 
-pub async fn add_pay(asset: u32, trade_id: StaticStr, from: StaticStr, to: StaticStr, amount: u64, gas: Vec<GasInfo>, hash: StaticStr)-> Result<()> {
-    if TRADES[asset as usize].contains(&trade_id).await { return Err(anyhow!("trade {} existed", trade_id )); }
-    let trade = Trade::pay(from, to, amount, gas, hash);
-    if account_start(asset, trade_id.clone(), &trade).await {
-        account_add(trade.to.clone(), asset, trade_id.clone(), None).await;
-        let _ = TRADES[asset as usize].insert(trade_id, trade).await;
-        Ok(())
-    } else { Err(anyhow!("{} have no enough amount", trade.from)) }
+
+
+pub async fn add_pay(asset: u32, trade_id: StaticStr, from: StaticStr, to: StaticStr, amount: u64, gas: Vec<GasInfo>, hash: StaticStr)-> LedgerResult<()> {
+    start_pay(asset, trade_id, Trade::pay(from, to, amount, gas, hash)).await
 }
 
-pub async fn complete_pay(asset: u32, trade_id: StaticStr, success: bool)-> bool {
-    if let Some(old) = TRADES[asset as usize].update(trade_id.clone(), |mut trade| if trade.modify(success) { Some(trade) } else { None } ).await {
-        if success {
-            account_success(asset, &old, true).await
-        } else {
-            account_modify(&old.from, |account| {
-                account.rollback(asset as usize, &old)
-            }).await
+pub async fn add_pay_auto(asset: u32, from: StaticStr, to: StaticStr, amount: u64, gas: Vec<GasInfo>, hash: StaticStr)-> LedgerResult<StaticStr> {
+    let trade_id = id::next_trade_id()?;
+    add_pay(asset, trade_id.clone(), from, to, amount, gas, hash).await.map(|_| trade_id )
+}
+
+pub async fn add_pay_with_approval(asset: u32, trade_id: StaticStr, from: StaticStr, to: StaticStr, amount: u64, gas: Vec<GasInfo>, hash: StaticStr)-> LedgerResult<()> {
+    let mut trade = Trade::pay(from, to, amount, gas, hash);          //资金同样锁定 审核通过之后才进入 Pending
+    trade.status = TransferStatus::Approving;
+    start_pay(asset, trade_id, trade).await
+}
+
+pub async fn add_batch_pay(asset: u32, trade_id: StaticStr, from: StaticStr, outputs: Vec<(StaticStr, u64)>, gas: Vec<GasInfo>, hash: StaticStr)-> LedgerResult<()> {      //合计金额和 gas 一次锁定 complete_pay 完成
+    if outputs.is_empty() { return Err(LedgerError::EmptyBatch(trade_id)); }
+    start_pay(asset, trade_id, Trade::batch_pay(from, outputs, gas, hash).ok_or(LedgerError::Overflow{asset})?).await
+}
+
+pub async fn add_pay_with_fee(asset: u32, trade_id: StaticStr, from: StaticStr, to: StaticStr, amount: u64, hash: StaticStr)-> LedgerResult<Vec<GasInfo>> {     //手续费按照 fee 规则计算 返回使用的 gas
+    let gas = fee::quote_fee(asset, &TransferType::Pay, amount)?;
+    add_pay(asset, trade_id, from, to, amount, gas.clone(), hash).await.map(|_| gas )
+}
+
+fn check_gas(gas: &[GasInfo])-> LedgerResult<()> {      //gas 的资产 id 决定账户余额列表的长度 必须是已有的资产
+    gas.iter().try_for_each(|g| asset::check_active(g.asset) )
+}
+
+async fn start_pay(asset: u32, trade_id: StaticStr, trade: Trade)-> LedgerResult<()> {
+    let _gate = GATE.read().await;
+    asset::check_active(asset)?;
+    check_gas(&trade.gas)?;
+    let manager = manager(asset)?;
+    let _reserved = manager.reserve(&trade_id).await?;
+    let _pending = wal::begin(wal::Op::Start{asset, trade_id: trade_id.clone(), trade: trade.clone()}).await?;
+    account_start(asset, trade_id.clone(), &trade).await?;
+    for to in trade.counterparties() {
+        account_add(to, asset, trade_id.clone(), trade.create_tick, None).await;
+    }
+    insert_started(&manager, asset, trade_id, trade).await
+}
+
+async fn insert_started(manager: &trade::TradeManager, asset: u32, trade_id: StaticStr, trade: Trade)-> LedgerResult<()> {        //资金已经锁定 保存失败时解锁
+    if let Err(e) = manager.insert(trade_id.clone(), trade.clone()).await {
+        if let Err(e) = account_modify(&trade.from, |account| account.rollback(asset as usize, &trade) ).await { log::error!("unlock {} {:?}", trade_id, e); }
+        account_forget(&trade.from, asset, &trade_id).await;
+        for to in trade.counterparties() {
+            account_forget(&to, asset, &trade_id).await;
         }
-    } else { false }
+        return Err(e);
+    }
+    journal::record(journal::lock(asset, &trade_id, &trade)).await;
+    Ok(())
 }
 
-pub async fn add_withdraw(asset: u32, trade_id: StaticStr, from: StaticStr, to: StaticStr, amount: u64, gas: Vec<GasInfo>, hash: StaticStr)-> Result<()> {
-    if TRADES[asset as usize].contains(&trade_id).await { return Err(anyhow!("trade {} existed", trade_id )); }
-    let trade = Trade::withdraw(from, to, amount, gas, hash);
-    if account_start(asset, trade_id.clone(), &trade).await {
-        let _ = TRADES[asset as usize].insert(trade_id, trade).await;
-        Ok(())        
-    } else { Err(anyhow!("{} have no enough amount", trade.from)) }
+pub async fn complete_pay(asset: u32, trade_id: StaticStr, success: bool)-> LedgerResult<()> {
+    complete_transfer(asset, trade_id, success).await
 }
 
-pub async fn complete_withdraw(asset: u32, trade_id: StaticStr, success: bool)-> bool {
-    if let Some(old) = TRADES[asset as usize].update(trade_id.clone(), |mut trade| {
-        if trade.modify(success) { Some(trade) } else { None } 
-    }).await {
-        if success {
-            account_success(asset, &old, true).await
-        } else {
-            account_modify(&old.from, |account| {
-                account.rollback(asset as usize, &old) 
-            }).await
+async fn complete_transfer(asset: u32, trade_id: StaticStr, success: bool)-> LedgerResult<()> {       //pay 和 withdraw 的完成流程相同
+    let _gate = GATE.read().await;
+    if let Some(transaction) = transaction::owner(asset, &trade_id) { return Err(LedgerError::InTransaction{trade_id, transaction}); }
+    finish_transfer(asset, trade_id, success).await
+}
+
+async fn finish_transfer(asset: u32, trade_id: StaticStr, success: bool)-> LedgerResult<()> {         //调用方持有 GATE
+    let _pending = wal::begin(wal::Op::Complete{asset, trade_id: trade_id.clone(), success}).await?;
+    let manager = manager(asset)?;
+    let modify = |trade: &mut Trade| trade.modify(success);
+    check_finish(&manager, asset, &trade_id, success, &modify).await?;
+    let old = manager.update(&trade_id, modify).await?;
+    apply_completed(asset, &trade_id, &old, success).await;
+    Ok(())
+}
+
+pub async fn add_withdraw(asset: u32, trade_id: StaticStr, from: StaticStr, to: StaticStr, amount: u64, gas: Vec<GasInfo>, hash: StaticStr)-> LedgerResult<()> {
+    start_withdraw(asset, trade_id, Trade::withdraw(from, to, amount, gas, hash)).await
+}
+
+pub async fn add_withdraw_auto(asset: u32, from: StaticStr, to: StaticStr, amount: u64, gas: Vec<GasInfo>, hash: StaticStr)-> LedgerResult<StaticStr> {
+    let trade_id = id::next_trade_id()?;
+    add_withdraw(asset, trade_id.clone(), from, to, amount, gas, hash).await.map(|_| trade_id )
+}
+
+pub async fn add_withdraw_with_approval(asset: u32, trade_id: StaticStr, from: StaticStr, to: StaticStr, amount: u64, gas: Vec<GasInfo>, hash: StaticStr)-> LedgerResult<()> {
+    let mut trade = Trade::withdraw(from, to, amount, gas, hash);
+    trade.status = TransferStatus::Approving;
+    start_withdraw(asset, trade_id, trade).await
+}
+
+pub async fn add_withdraw_with_fee(asset: u32, trade_id: StaticStr, from: StaticStr, to: StaticStr, amount: u64, hash: StaticStr)-> LedgerResult<Vec<GasInfo>> {
+    let gas = fee::quote_fee(asset, &TransferType::Withdraw, amount)?;
+    add_withdraw(asset, trade_id, from, to, amount, gas.clone(), hash).await.map(|_| gas )
+}
+
+async fn start_withdraw(asset: u32, trade_id: StaticStr, trade: Trade)-> LedgerResult<()> {
+    let _gate = GATE.read().await;
+    asset::check_active(asset)?;
+    check_gas(&trade.gas)?;
+    let manager = manager(asset)?;
+    let _reserved = manager.reserve(&trade_id).await?;
+    let _pending = wal::begin(wal::Op::Start{asset, trade_id: trade_id.clone(), trade: trade.clone()}).await?;
+    account_start(asset, trade_id.clone(), &trade).await?;
+    for to in trade.counterparties() {                          //和 load 一样 外部地址和 gas 接收方也记录这笔交易
+        account_add(to, asset, trade_id.clone(), trade.create_tick, None).await;
+    }
+    insert_started(&manager, asset, trade_id, trade).await
+}
+
+pub async fn complete_withdraw(asset: u32, trade_id: StaticStr, success: bool)-> LedgerResult<()> {
+    complete_transfer(asset, trade_id, success).await
+}
+
+pub async fn mark_broadcast(asset: u32, trade_id: StaticStr, hash: StaticStr)-> LedgerResult<()> {      //WaitBroadcast -> Pending 之后才能 complete
+    let _gate = GATE.read().await;
+    let manager = manager(asset)?;
+    let _hash = match manager.trade(&trade_id).await {
+        Some(trade) if trade.r#type == TransferType::Fund=> trade::reserve_fund_hash(&hash, asset, &trade_id).await?,
+        _=> None,
+    };
+    manager.update(&trade_id, |trade| trade.broadcast(hash.clone()) ).await.map(|_| () )
+}
+
+pub async fn list_wait_broadcast(asset: Option<u32>)-> LedgerResult<Vec<(u32, StaticStr, Trade)>> {         //None 返回所有资产 供签名服务拉取
+    if let Some(asset) = asset { manager(asset)?; }
+    let mut trades = Vec::new();
+    for (id, manager) in managers().into_iter().enumerate() {
+        if asset.is_none_or(|asset| asset as usize == id) {
+            trades.extend(manager.list_waiting().await.into_iter().map(|(trade_id, trade)| (id as u32, trade_id, trade) ));
         }
-    } else { false }
+    }
+    Ok(trades)
+}
+
+pub async fn list_approving(asset: u32)-> LedgerResult<Vec<(StaticStr, Trade)>> {
+    Ok(manager(asset)?.list_approving().await)
+}
+
+pub async fn approve(asset: u32, trade_id: StaticStr, operator: StaticStr)-> LedgerResult<()> {         //通过之后按照正常的 Pending 交易完成
+    let _gate = GATE.read().await;
+    manager(asset)?.update(&trade_id, |trade| trade.approve(operator.clone()) ).await.map(|_| () )
+}
+
+pub(crate) async fn expire(asset: u32, trade_id: StaticStr, reason: StaticStr)-> LedgerResult<()> {      //超时的 Pending 交易 和 complete(false) 一样回滚
+    let _gate = GATE.read().await;
+    if let Some(transaction) = transaction::owner(asset, &trade_id) { return Err(LedgerError::InTransaction{trade_id, transaction}); }     //事务中的交易只能一起 abort
+    let _pending = wal::begin(wal::Op::Complete{asset, trade_id: trade_id.clone(), success: false}).await?;
+    let manager = manager(asset)?;
+    let expire = |trade: &mut Trade| trade.expire(reason.clone());
+    check_finish(&manager, asset, &trade_id, false, &expire).await?;
+    let old = manager.update(&trade_id, expire).await?;
+    apply_completed(asset, &trade_id, &old, false).await;
+    Ok(())
+}
+
+pub async fn reject(asset: u32, trade_id: StaticStr, operator: StaticStr, reason: StaticStr)-> LedgerResult<()> {      //拒绝和 complete_withdraw(false) 一样回滚锁定的资金
+    let _gate = GATE.read().await;
+    let _pending = wal::begin(wal::Op::Complete{asset, trade_id: trade_id.clone(), success: false}).await?;
+    let manager = manager(asset)?;
+    let reject = |trade: &mut Trade| trade.reject(operator.clone(), reason.clone());
+    check_finish(&manager, asset, &trade_id, false, &reject).await?;
+    let old = manager.update(&trade_id, reject).await?;
+    apply_completed(asset, &trade_id, &old, false).await;
+    Ok(())
 }
 
 pub(crate) async fn add_trade(asset: u32, trade_id: StaticStr, trade: Trade) {           //加载初始化的数据, 
     match trade.r#type {
         TransferType::Fund=> {                                                          //充值来自与 level 1 所以不需要扣除 trade.from 的资产
-            account_add(trade.to.clone(), asset, trade_id.clone(), None).await;
+            account_add(trade.to.clone(), asset, trade_id.clone(), trade.create_tick, None).await;
             if trade.status == TransferStatus::Succeeded {
-                account_modify(&trade.to, |account| account.income(asset as usize, trade.amount) ).await;
+                let _ = account_modify(&trade.to, |account| account.income(asset as usize, trade.amount) ).await;
             }
         }
-        TransferType::Pay | TransferType::Gas=> {
-            account_add(trade.from.clone(), asset, trade_id.clone(), None).await;
-            account_add(trade.to.clone(), asset, trade_id.clone(), None).await;
-            if trade.status == TransferStatus::Succeeded {
-                account_success(asset, &trade, false).await;
-            } else if trade.status != TransferStatus::Failed {
-                account_start(asset, trade_id, &trade).await;
+        TransferType::Pay | TransferType::Gas | TransferType::BatchPay | TransferType::Withdraw=> {
+            account_add(trade.from.clone(), asset, trade_id.clone(), trade.create_tick, None).await;
+            for to in trade.counterparties() {
+                account_add(to, asset, trade_id.clone(), trade.create_tick, None).await;
             }
-        }
-        TransferType::Withdraw=> {
-            account_add(trade.from.clone(), asset, trade_id.clone(), None).await;
-            if trade.to != trade.from {
-                account_add(trade.to.clone(), asset, trade_id.clone(), None).await;    
-            }
-            if trade.status == TransferStatus::Succeeded {
-                account_success(asset, &trade, false).await;
+            let loaded = if trade.status == TransferStatus::Succeeded {
+                account_success(asset, &trade, false).await
             } else if trade.status != TransferStatus::Failed {
-                account_start(asset, trade_id, &trade).await;
+                account_start(asset, trade_id.clone(), &trade).await
+            } else {
+                Ok(())
+            };
+            if let Err(e) = loaded {                            //余额和交易不一致 由 reconcile 处理
+                log::error!("load trade {} {:?}", trade_id, e);
+                let _ = WARNINGS.insert((asset, trade.from.clone()));
             }
         }
         TransferType::AirDrop=> {
-            //account_add(trade.to, asset, trade_id.clone(), Some(trade.amount)).await;
+            //account_add(trade.to, asset, trade_id.clone(), trade.create_tick, Some(trade.amount)).await;
         }
         _=> {}
     }
 }
 
-pub fn load_all()-> std::time::Duration {
+async fn settle(asset: u32, trade: &Trade, status: &TransferStatus) {        //快照之后状态发生变化的交易 按照实时操作补做
+    if trade.status == *status { return }
+    match (&trade.r#type, &trade.status) {
+        (TransferType::Fund, TransferStatus::Succeeded)=> { let _ = account_modify(&trade.to, |account| account.income(asset as usize, trade.amount) ).await; }
+        (TransferType::Pay | TransferType::Gas | TransferType::Withdraw | TransferType::BatchPay, TransferStatus::Succeeded)=> { let _ = account_success(asset, trade, true).await; }
+        (TransferType::Pay | TransferType::Gas | TransferType::Withdraw | TransferType::BatchPay, TransferStatus::Failed)=> { let _ = account_modify(&trade.from, |account| account.rollback(asset as usize, trade) ).await; }
+        _=> {}
+    }
+}
+
+pub fn load_all()-> std::time::Duration {            //有快照的时候只重放快照之后的交易
     let start = std::time::Instant::now();
+    let restored = Arc::new(snapshot::restore());
     let mut tasks = Vec::new();
-    for asset in 0..trade::ASSET_NUM {
+    for (asset, manager) in managers().into_iter().enumerate() {
+        let restored = restored.clone();
         tasks.push(std::thread::spawn(move || {
             let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
-            TRADES[asset].store.load_all(move |id, trade: Trade| {
-                rt.block_on(async move {            //同一个 asset 的插入顺序需要保证 所以创建一个 runtime
-                    TRADES[asset].add_trade(id.clone(), trade.clone()).await;
-                    add_trade(asset as u32, id, trade).await;
+            let offset = restored.as_ref().as_ref().and_then(|r| r.offsets.get(asset).cloned() ).unwrap_or(0);
+            let mut index = 0;
+            manager.store.load_all(&mut |id, trade: Trade| {
+                rt.block_on(async {            //同一个 asset 的插入顺序需要保证 所以创建一个 runtime
+                    if !manager.add_trade(id.clone(), trade.clone()).await {
+                        log::warn!("asset {} trade {} listed twice", asset, id);
+                    } else if index >= offset {
+                        add_trade(asset as u32, id, trade).await;
+                    } else if let Some(status) = restored.as_ref().as_ref().and_then(|r| r.open.get(&(asset as u32, id)) ) {
+                        settle(asset as u32, &trade, status).await;
+                    }
                 });
+                index += 1;
             }).unwrap();
+            manager.listed.store(index, std::sync::atomic::Ordering::SeqCst);      //快照按照同样的计数保存位置
         }));
     }
     
     for t in tasks {
-        let _ = t.join();
+        if let Err(e) = t.join() { std::panic::resume_unwind(e) }      //部分加载的资产不能继续服务
     }
+    let recovered = wal::recover();
+    if recovered > 0 { log::warn!("recovered {} wal entries", recovered); }
     std::time::Instant::now().duration_since(start)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+pub(crate) fn test_init() {                     //所有测试共用一个内存 store 账户和交易 id 各自不同
+    static INIT: std::sync::Once = std::sync::Once::new();
+    INIT.call_once(|| {
+        let log = config::LogConfig{level: "off".to_string(), file: None};
+        config::init(config::Config{store: "memory".to_string(), log, ..Default::default()}).unwrap();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+
+    fn account(amounts: Vec<(u64, u64)>)-> Account {
+        Account{amounts, trades: BTreeSet::new()}
+    }
+
+    #[test]
+    fn transact_overflow_changes_nothing() {
+        let mut a = account(vec![(5, 0), (u64::MAX, 0)]);
+        assert!(matches!(a.transact(&[(0, -1, 1), (1, 1, 0)]), Err(LedgerError::Overflow{asset: 1})));
+        assert_eq!(a.amounts, vec![(5, 0), (u64::MAX, 0)]);
+    }
+
+    #[test]
+    fn transact_underflow_changes_nothing() {
+        let mut a = account(vec![(5, 0), (7, 3)]);
+        assert!(matches!(a.transact(&[(1, 3, -3), (0, -6, 6)]), Err(LedgerError::Underflow{asset: 0})));
+        assert_eq!(a.amounts, vec![(5, 0), (7, 3)]);
+        a.transact(&[(1, 3, -3), (0, -5, 5)]).unwrap();
+        assert_eq!(a.amounts, vec![(0, 5), (10, 0)]);
+    }
+
+    #[test]
+    fn lock_sums_gas_in_the_same_asset() {
+        test_init();
+        let mut a = account(vec![(100, 0); ASSETS.len()]);
+        let trade = Trade::pay(Cow::from("a"), Cow::from("b"), 60, vec![GasInfo::new(0, 30, Cow::from("g")), GasInfo::new(0, 20, Cow::from("g"))], Cow::from(""));
+        assert!(matches!(a.lock(0, &trade), Err(LedgerError::InsufficientGas{asset: 0, needed: 110, available: 100})));
+        assert_eq!(a.amount(0), (100, 0));
+        let trade = Trade::pay(Cow::from("a"), Cow::from("b"), 60, vec![GasInfo::new(0, 30, Cow::from("g")), GasInfo::new(0, 10, Cow::from("g"))], Cow::from(""));
+        a.lock(0, &trade).unwrap();
+        assert_eq!(a.amount(0), (0, 100));
+    }
+
+    #[test]
+    fn lock_rejects_unknown_gas_asset() {
+        test_init();
+        let mut a = account(vec![(100, 0); ASSETS.len()]);
+        let trade = Trade::pay(Cow::from("a"), Cow::from("b"), 1, vec![GasInfo::new(1_000_000, 0, Cow::from("g"))], Cow::from(""));
+        assert!(matches!(a.lock(0, &trade), Err(LedgerError::UnknownAsset(_))));
+        assert_eq!(a.amounts.len(), ASSETS.len());
+    }
+
+    #[tokio::test]
+    async fn memory_fund_pay_withdraw_lifecycle() {
+        test_init();
+        let s = |v: &str| Cow::from(v.to_string());
+        let amount = |account: &'static str| async move { get_amount(&Cow::from(account)).await.map(|a| a[0] ) };
+        add_fund(0, s("lc-f"), s("x"), s("lc-alice"), 1000, vec![], s("lc-hash")).await.unwrap();
+        assert_eq!(amount("lc-alice").await, Some((0, 0)));
+        assert!(matches!(complete_fund(0, s("lc-f"), true).await, Err(LedgerError::InvalidTransition(_))));
+        mark_broadcast(0, s("lc-f"), s("lc-hash")).await.unwrap();
+        complete_fund(0, s("lc-f"), true).await.unwrap();
+        assert_eq!(amount("lc-alice").await, Some((1000, 0)));
+
+        add_pay(0, s("lc-p"), s("lc-alice"), s("lc-bob"), 300, vec![GasInfo::new(0, 10, s("lc-gas"))], s("")).await.unwrap();
+        assert_eq!(amount("lc-alice").await, Some((690, 310)));
+        assert!(matches!(add_pay(0, s("lc-p"), s("lc-alice"), s("lc-bob"), 1, vec![], s("")).await, Err(LedgerError::DuplicateTrade(_))));
+        assert!(matches!(add_pay(0, s("lc-p2"), s("lc-alice"), s("lc-bob"), 691, vec![], s("")).await, Err(LedgerError::InsufficientBalance{..})));
+        complete_pay(0, s("lc-p"), true).await.unwrap();
+        assert_eq!(amount("lc-alice").await, Some((690, 0)));
+        assert_eq!(amount("lc-bob").await, Some((300, 0)));
+        assert_eq!(amount("lc-gas").await, Some((10, 0)));
+
+        add_withdraw(0, s("lc-w"), s("lc-alice"), s("lc-external"), 200, vec![], s("")).await.unwrap();
+        assert_eq!(amount("lc-alice").await, Some((490, 200)));
+        complete_withdraw(0, s("lc-w"), false).await.unwrap();
+        assert_eq!(amount("lc-alice").await, Some((690, 0)));
+        assert!(matches!(complete_withdraw(0, s("lc-w"), true).await, Err(LedgerError::InvalidTransition(_))));
+
+        let manager = manager(0).unwrap();
+        assert_eq!(manager.store.get(&s("lc-p")).map(|t| t.status ), Some(TransferStatus::Succeeded));
+        assert_eq!(manager.store.get(&s("lc-w")).map(|t| t.status ), Some(TransferStatus::Failed));
+        let journal = journal::get_journal("lc-alice");
+        assert_eq!(journal.iter().map(|e| e.available ).sum::<i128>(), 690);
+        assert_eq!(journal.iter().map(|e| e.locked ).sum::<i128>(), 0);
+    }
+
+    #[tokio::test]
+    async fn unknown_asset_is_an_error() {
+        test_init();
+        assert!(matches!(get_trades(u32::MAX, &Cow::from("ua-alice"), false).await, Err(LedgerError::UnknownAsset(_))));
+        assert!(matches!(list_approving(u32::MAX).await, Err(LedgerError::UnknownAsset(_))));
+        assert!(matches!(list_wait_broadcast(Some(u32::MAX)).await, Err(LedgerError::UnknownAsset(_))));
+    }
+
+    #[tokio::test]
+    async fn complete_without_lock_keeps_pending() {
+        test_init();
+        let s = |v: &str| Cow::from(v.to_string());
+        account_add(s("nl-alice"), 0, s("nl-p"), 0, Some(100)).await;
+        let manager = manager(0).unwrap();
+        manager.insert(s("nl-p"), Trade::pay(s("nl-alice"), s("nl-bob"), 40, vec![], s(""))).await.unwrap();      //和加载时锁定失败一样 没有锁定的资金
+        assert!(matches!(complete_pay(0, s("nl-p"), true).await, Err(LedgerError::Underflow{asset: 0})));
+        assert_eq!(manager.trade(&s("nl-p")).await.map(|t| t.status ), Some(TransferStatus::Pending));
+        assert_eq!(get_amount(&s("nl-alice")).await.map(|a| a[0] ), Some((100, 0)));
+        assert_eq!(get_amount(&s("nl-bob")).await, None);
+        assert!(matches!(complete_pay(0, s("nl-p"), false).await, Err(LedgerError::Underflow{asset: 0})));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_funds_share_no_hash() {
+        test_init();
+        for i in 0..50 {
+            let (a, b) = (Cow::from(format!("ch-a{}", i)), Cow::from(format!("ch-b{}", i)));
+            let hash = Cow::from(format!("ch-hash{}", i));
+            let (ra, rb) = tokio::join!(
+                tokio::spawn(add_fund(0, a, Cow::from("x"), Cow::from("ch-alice"), 1, vec![], hash.clone())),
+                tokio::spawn(add_fund(0, b, Cow::from("x"), Cow::from("ch-alice"), 1, vec![], hash.clone())),
+            );
+            let results = [ra.unwrap(), rb.unwrap()];
+            assert_eq!(results.iter().filter(|r| r.is_ok() ).count(), 1);
+            assert!(results.iter().any(|r| matches!(r, Err(LedgerError::DuplicateHash{..})) ));
+        }
+    }
+
+    #[tokio::test]
+    async fn memory_batch_pay() {
+        test_init();
+        let s = |v: &str| Cow::from(v.to_string());
+        let amount = |account: &'static str| async move { get_amount(&Cow::from(account)).await.map(|a| a[0] ) };
+        add_fund(0, s("bp-f"), s("x"), s("bp-alice"), 1000, vec![], s("bp-hash")).await.unwrap();
+        mark_broadcast(0, s("bp-f"), s("bp-hash")).await.unwrap();
+        complete_fund(0, s("bp-f"), true).await.unwrap();
+
+        let outputs = vec![(s("bp-bob"), 100), (s("bp-carol"), 50), (s("bp-bob"), 25)];
+        assert!(matches!(add_batch_pay(0, s("bp-p0"), s("bp-alice"), vec![], vec![], s("")).await, Err(LedgerError::EmptyBatch(_))));
+        add_batch_pay(0, s("bp-p1"), s("bp-alice"), outputs.clone(), vec![GasInfo::new(0, 5, s("bp-gas"))], s("")).await.unwrap();
+        assert_eq!(amount("bp-alice").await, Some((820, 180)));
+        for account in ["bp-alice", "bp-bob", "bp-carol", "bp-gas"] {
+            let trades = get_trades(0, &s(account), false).await.unwrap();
+            assert_eq!(trades.iter().filter(|t| t.0 == "bp-p1" ).count(), 1, "{}", account);
+        }
+        complete_pay(0, s("bp-p1"), true).await.unwrap();
+        assert_eq!(amount("bp-alice").await, Some((820, 0)));
+        assert_eq!(amount("bp-bob").await, Some((125, 0)));
+        assert_eq!(amount("bp-carol").await, Some((50, 0)));
+        assert_eq!(amount("bp-gas").await, Some((5, 0)));
+
+        add_batch_pay(0, s("bp-p2"), s("bp-alice"), outputs, vec![GasInfo::new(0, 5, s("bp-gas"))], s("")).await.unwrap();
+        assert_eq!(amount("bp-alice").await, Some((640, 180)));
+        complete_pay(0, s("bp-p2"), false).await.unwrap();
+        assert_eq!(amount("bp-alice").await, Some((820, 0)));
+        assert_eq!(amount("bp-bob").await, Some((125, 0)));
+        assert_eq!(amount("bp-gas").await, Some((5, 0)));
+    }
+}
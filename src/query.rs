@@ -0,0 +1,152 @@
+use std::borrow::Cow;
+use super::trade::{self, StaticStr, Trade, TransferType, TransferStatus};
+use super::error::{LedgerError, LedgerResult};
+use super::ACCOUNTS;
+
+#[derive(Clone, Debug)]
+pub struct TradeQuery {
+    pub asset: Option<u32>,                     //None 查询所有资产
+    pub r#type: Option<TransferType>,
+    pub status: Option<TransferStatus>,
+    pub counterparty: Option<StaticStr>,
+    pub created: Option<(i64, i64)>,            //[开始, 结束) 秒
+    pub updated: Option<(i64, i64)>,
+    pub descend: bool,
+    pub limit: usize,
+    pub cursor: Option<StaticStr>,              //上一页返回的 next
+}
+
+impl Default for TradeQuery {
+    fn default()-> Self {
+        Self{asset: None, r#type: None, status: None, counterparty: None, created: None, updated: None, descend: true, limit: 50, cursor: None}
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TradePage {
+    pub trades: Vec<(u32, StaticStr, Trade)>,
+    pub next: Option<StaticStr>,                //没有更多数据为 None
+}
+
+type Key = (i64, u32, StaticStr);               //按照 (create_tick, asset, trade_id) 排序 保证翻页稳定
+
+fn encode(key: &Key)-> StaticStr {
+    Cow::from(format!("{}:{}:{}", key.0, key.1, key.2))
+}
+
+fn decode(cursor: &str)-> LedgerResult<Key> {
+    let mut parts = cursor.splitn(3, ':');
+    let tick = parts.next().and_then(|t| t.parse().ok() );
+    let asset = parts.next().and_then(|a| a.parse().ok() );
+    match (tick, asset, parts.next()) {
+        (Some(tick), Some(asset), Some(id))=> Ok((tick, asset, Cow::from(id.to_string()))),
+        _=> Err(LedgerError::InvalidCursor(Cow::from(cursor.to_string()))),
+    }
+}
+
+fn within(range: &Option<(i64, i64)>, tick: i64)-> bool {
+    range.is_none_or(|(start, end)| tick >= start && tick < end )
+}
+
+impl TradeQuery {
+    fn matches(&self, account: &str, trade: &Trade)-> bool {
+        self.r#type.as_ref().is_none_or(|t| *t == trade.r#type ) && self.status.as_ref().is_none_or(|s| *s == trade.status )
+            && self.counterparty.as_ref().is_none_or(|c| if trade.from == account { trade.to == *c || trade.outputs.iter().any(|o| o.0 == *c ) } else { trade.from == *c } )
+            && within(&self.created, trade.create_tick) && within(&self.updated, trade.update_tick)
+    }
+}
+
+async fn keys_after(account: &StaticStr, query: &TradeQuery, after: Option<&Key>, count: usize)-> Vec<Key> {      //从 cursor 之后按顺序取出 count 个 不加载交易内容
+    use std::ops::Bound::{Excluded, Unbounded};
+    ACCOUNTS.read_async(account, |_, account| {
+        let keys: Box<dyn Iterator<Item = &Key>> = match (after, query.descend) {
+            (Some(after), true)=> Box::new(account.trades.range((Unbounded, Excluded(after))).rev()),
+            (Some(after), false)=> Box::new(account.trades.range((Excluded(after), Unbounded))),
+            (None, true)=> Box::new(account.trades.iter().rev()),
+            (None, false)=> Box::new(account.trades.iter()),
+        };
+        keys.filter(|k| query.asset.is_none_or(|a| a == k.1 ) ).take(count).cloned().collect()
+    }).await.unwrap_or_default()
+}
+
+pub async fn query_trades(account: &StaticStr, query: &TradeQuery)-> LedgerResult<TradePage> {
+    let mut after = query.cursor.as_ref().map(|c| decode(c) ).transpose()?;
+    let limit = query.limit.max(1);
+    let mut trades = Vec::new();
+    while trades.len() <= limit {                   //多取一个判断是否还有下一页 被过滤掉的继续往后取
+        let keys = keys_after(account, query, after.as_ref(), limit + 1 - trades.len()).await;
+        if keys.is_empty() { break }
+        after = keys.last().cloned();
+        for key in keys {
+            if let Some(trade) = trade::manager(key.1)?.trade(&key.2).await {
+                if query.matches(account, &trade) { trades.push((key, trade)); }
+            }
+        }
+    }
+    let next = if trades.len() > limit { trades.truncate(limit); trades.last().map(|t| encode(&t.0) ) } else { None };
+    let trades = trades.into_iter().map(|((_, asset, id), trade)| (asset, id, trade) ).collect();
+    Ok(TradePage{trades, next})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{add_fund, add_pay, complete_fund, mark_broadcast, test_init};
+
+    fn s(v: &str)-> StaticStr {
+        Cow::from(v.to_string())
+    }
+
+    async fn pages(account: &StaticStr, r#type: Option<TransferType>, descend: bool, limit: usize)-> Vec<StaticStr> {
+        let mut ids = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = query_trades(account, &TradeQuery{r#type: r#type.clone(), descend, limit, cursor, ..Default::default()}).await.unwrap();
+            assert!(page.trades.len() <= limit);
+            ids.extend(page.trades.into_iter().map(|t| t.1 ));
+            match page.next {
+                Some(next)=> cursor = Some(next),
+                None=> return ids,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn cursor_pages_cover_every_trade_once() {
+        test_init();
+        add_fund(0, s("qp-f"), s("x"), s("qp-alice"), 100, vec![], s("qp-hash")).await.unwrap();
+        mark_broadcast(0, s("qp-f"), s("qp-hash")).await.unwrap();
+        complete_fund(0, s("qp-f"), true).await.unwrap();
+        for i in 0..5 {
+            add_pay(0, s(&format!("qp-{}", i)), s("qp-alice"), s("qp-bob"), 1, vec![], s("")).await.unwrap();
+        }
+        let account = s("qp-alice");
+        for descend in [true, false] {
+            let all = query_trades(&account, &TradeQuery{descend, limit: 100, ..Default::default()}).await.unwrap();
+            assert_eq!(all.trades.len(), 6);
+            assert!(all.next.is_none());
+            let keys: Vec<Key> = all.trades.iter().map(|t| (t.2.create_tick, t.0, t.1.clone()) ).collect();
+            let mut sorted = keys.clone();
+            sorted.sort();
+            if descend { sorted.reverse(); }
+            assert_eq!(keys, sorted);
+            let ids: Vec<StaticStr> = all.trades.into_iter().map(|t| t.1 ).collect();
+            for limit in [1, 2, 4, 6, 7] {
+                assert_eq!(pages(&account, None, descend, limit).await, ids, "descend {} limit {}", descend, limit);
+            }
+            let pays: Vec<StaticStr> = ids.iter().filter(|id| *id != "qp-f" ).cloned().collect();          //过滤掉的交易不占用分页
+            for limit in [1, 2, 5] {
+                assert_eq!(pages(&account, Some(TransferType::Pay), descend, limit).await, pays, "descend {} limit {}", descend, limit);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_account_and_bad_cursor() {
+        test_init();
+        let account = s("qp-nobody");
+        assert!(query_trades(&account, &TradeQuery::default()).await.unwrap().trades.is_empty());
+        assert!(matches!(query_trades(&account, &TradeQuery{cursor: Some(s("garbage")), ..Default::default()}).await, Err(LedgerError::InvalidCursor(_))));
+        assert_eq!(decode(&encode(&(12, 3, s("a:b")))).unwrap(), (12, 3, s("a:b")));
+    }
+}
@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+use super::trade::{self, StaticStr, FUND_ADDR};
+use super::{journal, ACCOUNTS};
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct Mismatch {
+    pub account: StaticStr,
+    pub asset: u32,
+    pub expected: (i128, i128),                 //按交易重新计算的 (可用, 锁定)
+    pub actual: (u64, u64),                     //ACCOUNTS 中的余额 账户不存在为 (0, 0)
+    pub trades: Vec<StaticStr>,                 //涉及这个账户这个资产的所有交易
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize)]
+pub struct Report {
+    pub trades: usize,
+    pub accounts: usize,
+    pub mismatches: Vec<Mismatch>,
+}
+
+#[derive(Default)]
+struct Expected {
+    amount: (i128, i128),
+    trades: Vec<StaticStr>,
+}
+
+pub async fn reconcile()-> Report {            //不加锁 逐个 bucket 扫描 正在进行中的交易可能产生临时的差异
+    let mut report = Report::default();
+    let mut expected: HashMap<(StaticStr, u32), Expected> = HashMap::new();
+    for (asset, manager) in trade::managers().into_iter().enumerate() {
+        let mut trades = Vec::new();
+        manager.trades.scan_async(|id, trade| trades.push((id.clone(), trade.clone())) ).await;
+        report.trades += trades.len();
+        for (id, trade) in trades {
+            for entry in journal::effects(asset as u32, &id, &trade) {
+                if entry.account == *FUND_ADDR { continue }
+                let e = expected.entry((entry.account, entry.asset)).or_default();
+                e.amount.0 += entry.available;
+                e.amount.1 += entry.locked;
+                if e.trades.last() != Some(&id) { e.trades.push(id.clone()); }
+            }
+        }
+    }
+
+    let mut actual: HashMap<(StaticStr, u32), (u64, u64)> = HashMap::new();
+    ACCOUNTS.scan_async(|name, account| {
+        report.accounts += 1;
+        for (asset, amount) in account.amounts.iter().enumerate() {
+            if *amount != (0, 0) { actual.insert((name.clone(), asset as u32), *amount); }
+        }
+    }).await;
+
+    for ((account, asset), e) in expected.iter_mut() {
+        let live = actual.remove(&(account.clone(), *asset)).unwrap_or((0, 0));
+        if (live.0 as i128, live.1 as i128) != e.amount {
+            report.mismatches.push(Mismatch{account: account.clone(), asset: *asset, expected: e.amount, actual: live, trades: std::mem::take(&mut e.trades)});
+        }
+    }
+    for ((account, asset), live) in actual {              //有余额但是没有任何交易
+        report.mismatches.push(Mismatch{account, asset, expected: (0, 0), actual: live, trades: Vec::new()});
+    }
+    report
+}
@@ -0,0 +1,184 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use super::trade::{GasInfo, StaticStr, TransferType};
+use super::error::LedgerError;
+use super::transaction::Leg;
+
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Deserialize)]
+struct AddParams {
+    asset: u32,
+    trade_id: Option<StaticStr>,                //不提供则使用 snowflake 生成
+    from: StaticStr,
+    to: StaticStr,
+    amount: u64,
+    #[serde(default)]
+    gas: Vec<GasInfo>,
+    #[serde(default)]
+    hash: StaticStr,
+}
+
+#[derive(Deserialize)]
+struct BatchParams {
+    asset: u32,
+    trade_id: Option<StaticStr>,
+    from: StaticStr,
+    outputs: Vec<(StaticStr, u64)>,
+    #[serde(default)]
+    gas: Vec<GasInfo>,
+    #[serde(default)]
+    hash: StaticStr,
+}
+
+#[derive(Deserialize)]
+struct QuoteParams {
+    asset: u32,
+    r#type: TransferType,
+    amount: u64,
+}
+
+#[derive(Deserialize)]
+struct CompleteParams {
+    asset: u32,
+    trade_id: StaticStr,
+    success: bool,
+}
+
+#[derive(Deserialize)]
+struct BroadcastParams {
+    asset: u32,
+    trade_id: StaticStr,
+    hash: StaticStr,
+}
+
+#[derive(Deserialize)]
+struct TransactionParams {
+    #[serde(default)]
+    legs: Vec<Leg>,
+    #[serde(default)]
+    id: StaticStr,
+}
+
+#[derive(Deserialize)]
+struct AccountParams {
+    account: StaticStr,
+    asset: Option<u32>,
+    #[serde(default)]
+    descend: bool,
+}
+
+pub enum RpcError {
+    Parse(String),
+    Method(String),
+    Params(String),
+    Ledger(LedgerError),
+}
+
+impl RpcError {
+    fn to_json(&self)-> Value {                 //-32xxx 是 JSON-RPC 的标准错误 账本错误使用 -32000
+        match self {
+            Self::Parse(e)=> json!({"code": -32700, "message": e}),
+            Self::Method(m)=> json!({"code": -32601, "message": format!("unknow method {}", m)}),
+            Self::Params(e)=> json!({"code": -32602, "message": e}),
+            Self::Ledger(e)=> json!({"code": -32000, "message": e.to_string(), "data": format!("{:?}", e)}),
+        }
+    }
+}
+
+impl From<LedgerError> for RpcError {
+    fn from(e: LedgerError)-> Self {
+        Self::Ledger(e)
+    }
+}
+
+fn params<T: for<'de> Deserialize<'de>>(params: Value)-> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::Params(e.to_string()) )
+}
+
+async fn add(method: &str, p: AddParams)-> Result<Value, RpcError> {
+    let trade_id = match p.trade_id {
+        Some(id)=> id,
+        None=> super::id::next_trade_id()?,
+    };
+    match method {          //按照 fee 规则收取的 gas 一起返回
+        "add_pay_with_fee"=> return Ok(json!({"trade_id": trade_id.clone(), "gas": super::add_pay_with_fee(p.asset, trade_id, p.from, p.to, p.amount, p.hash).await?})),
+        "add_withdraw_with_fee"=> return Ok(json!({"trade_id": trade_id.clone(), "gas": super::add_withdraw_with_fee(p.asset, trade_id, p.from, p.to, p.amount, p.hash).await?})),
+        "add_pay"=> super::add_pay(p.asset, trade_id.clone(), p.from, p.to, p.amount, p.gas, p.hash).await?,
+        "add_withdraw"=> super::add_withdraw(p.asset, trade_id.clone(), p.from, p.to, p.amount, p.gas, p.hash).await?,
+        _=> super::add_fund(p.asset, trade_id.clone(), p.from, p.to, p.amount, p.gas, p.hash).await?,
+    }
+    Ok(json!(trade_id))
+}
+
+async fn dispatch(method: &str, p: Value)-> Result<Value, RpcError> {
+    match method {
+        "add_pay" | "add_withdraw" | "add_fund" | "add_pay_with_fee" | "add_withdraw_with_fee"=> add(method, params(p)?).await,
+        "add_batch_pay"=> {                     //complete_pay 完成
+            let p: BatchParams = params(p)?;
+            let trade_id = match p.trade_id {
+                Some(id)=> id,
+                None=> super::id::next_trade_id()?,
+            };
+            super::add_batch_pay(p.asset, trade_id.clone(), p.from, p.outputs, p.gas, p.hash).await?;
+            Ok(json!(trade_id))
+        }
+        "quote_fee"=> {                         //gas 由 fee 规则计算 下单之前查询
+            let p: QuoteParams = params(p)?;
+            Ok(json!(super::quote_fee(p.asset, &p.r#type, p.amount)?))
+        }
+        "complete_pay" | "complete_withdraw" | "complete_fund"=> {
+            let p: CompleteParams = params(p)?;
+            match method {
+                "complete_pay"=> super::complete_pay(p.asset, p.trade_id, p.success).await?,
+                "complete_withdraw"=> super::complete_withdraw(p.asset, p.trade_id, p.success).await?,
+                _=> super::complete_fund(p.asset, p.trade_id, p.success).await?,
+            }
+            Ok(Value::Null)
+        }
+        "mark_broadcast"=> {                    //充值需要先进入 Pending 才能 complete_fund
+            let p: BroadcastParams = params(p)?;
+            super::mark_broadcast(p.asset, p.trade_id, p.hash).await?;
+            Ok(Value::Null)
+        }
+        "begin_transaction" | "commit_transaction" | "abort_transaction" | "get_transaction"=> {      //legs 一起锁定 之后一起 commit 或者 abort
+            let p: TransactionParams = params(p)?;
+            match method {
+                "begin_transaction"=> Ok(json!(super::begin_transaction(p.legs).await?)),
+                "commit_transaction"=> Ok(json!(super::commit_transaction(p.id).await?)),
+                "abort_transaction"=> Ok(json!(super::abort_transaction(p.id).await?)),
+                _=> Ok(json!(super::transaction::get_transaction(&p.id))),
+            }
+        }
+        "get_amount"=> {
+            let p: AccountParams = params(p)?;
+            Ok(json!(super::get_amount(&p.account).await))
+        }
+        "get_trades"=> {
+            let p: AccountParams = params(p)?;
+            let asset = p.asset.ok_or(RpcError::Params("missing asset".to_string()))?;
+            Ok(json!(super::get_trades(asset, &p.account, p.descend).await?))
+        }
+        "reconcile"=> Ok(json!(super::reconcile::reconcile().await)),
+        _=> Err(RpcError::Method(method.to_string())),
+    }
+}
+
+pub async fn handle(line: &str)-> String {     //一行一个请求 返回一行响应
+    let (id, result) = match serde_json::from_str::<Request>(line) {
+        Ok(request)=> (request.id, dispatch(&request.method, request.params).await),
+        Err(e)=> (Value::Null, Err(RpcError::Parse(e.to_string()))),
+    };
+    let response = match result {
+        Ok(result)=> json!({"jsonrpc": "2.0", "id": id, "result": result}),
+        Err(e)=> json!({"jsonrpc": "2.0", "id": id, "error": e.to_json()}),
+    };
+    response.to_string()
+}
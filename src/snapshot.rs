@@ -0,0 +1,72 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use super::trade::{self, StaticStr, TransferStatus};
+use super::store::{meta_write, BACKEND};
+use super::{Account, ACCOUNTS, GATE};
+
+const SNAPSHOT_KEY: &str = "@snapshot";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub tick: i64,
+    pub offsets: Vec<usize>,                            //每个资产已经包含在快照中的交易列表条目数量 重复的 id 也计数
+    pub open: Vec<(u32, StaticStr, TransferStatus)>,    //快照时还没有结束的交易 加载时需要根据最新状态补做
+    pub accounts: Vec<(StaticStr, Account)>,
+}
+
+pub async fn take_snapshot()-> bool {
+    let snapshot = {
+        let _gate = GATE.write().await;                 //等待进行中的操作结束 保证余额和交易位置一致
+        let mut offsets = Vec::new();
+        let mut open = Vec::new();
+        for (asset, manager) in trade::managers().into_iter().enumerate() {
+            offsets.push(manager.listed.load(std::sync::atomic::Ordering::SeqCst));
+            manager.trades.scan_async(|id, trade| {
+                if trade.status != TransferStatus::Succeeded && trade.status != TransferStatus::Failed {
+                    open.push((asset as u32, id.clone(), trade.status.clone()));
+                }
+            }).await;
+        }
+        let mut accounts = Vec::new();
+        ACCOUNTS.scan_async(|name, account| accounts.push((name.clone(), account.clone())) ).await;
+        Snapshot{tick: chrono::Utc::now().timestamp(), offsets, open, accounts}
+    };
+    match rmp_serde::to_vec(&snapshot) {
+        Ok(buf)=> meta_write(move |meta| meta.set(SNAPSHOT_KEY, &buf) ).await,
+        Err(e)=> {
+            log::error!("snapshot {:?}", e);
+            false
+        }
+    }
+}
+
+pub fn spawn_snapshots(interval: std::time::Duration)-> tokio::task::JoinHandle<()> {        //定期保存快照 需要在 tokio runtime 中调用
+    tokio::spawn(async move {
+        let mut timer = tokio::time::interval(interval);
+        timer.tick().await;
+        loop {
+            timer.tick().await;
+            if !take_snapshot().await { log::error!("snapshot not stored"); }
+        }
+    })
+}
+
+pub(crate) struct Restored {
+    pub offsets: Vec<usize>,
+    pub open: HashMap<(u32, StaticStr), TransferStatus>,
+}
+
+pub(crate) fn restore()-> Option<Restored> {           //快照不存在或者和交易列表不一致 返回 None 全量加载
+    let snapshot: Snapshot = BACKEND.meta().get(SNAPSHOT_KEY).and_then(|buf| rmp_serde::from_slice(&buf).map_err(|e| log::error!("snapshot corrupt {:?}", e) ).ok() )?;
+    let managers = trade::managers();
+    if snapshot.offsets.len() > managers.len() || snapshot.offsets.iter().zip(managers.iter()).any(|(offset, manager)| *offset > manager.store.len() ) {
+        log::error!("snapshot {} not match trade list", snapshot.tick);
+        return None;
+    }
+    ACCOUNTS.clear();
+    for (name, account) in snapshot.accounts {
+        let _ = ACCOUNTS.insert(name, account);
+    }
+    log::info!("restore snapshot {} offsets {:?}", snapshot.tick, snapshot.offsets);
+    Some(Restored{offsets: snapshot.offsets, open: snapshot.open.into_iter().map(|(asset, id, status)| ((asset, id), status) ).collect()})
+}
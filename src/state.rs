@@ -0,0 +1,111 @@
+use std::borrow::Cow;
+use super::trade::{StaticStr, Trade, TransferType, TransferStatus};
+use TransferStatus::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Approve,
+    Reject,
+    Broadcast,
+    Succeed,
+    Fail,
+}
+
+type Transition = (TransferStatus, Action, TransferStatus);
+
+const FUND: &[Transition] = &[(WaitBroadcast, Action::Broadcast, Pending), (Pending, Action::Succeed, Succeeded), (Pending, Action::Fail, Failed)];
+const PAY: &[Transition] = &[(Approving, Action::Approve, Pending), (Approving, Action::Reject, Failed),
+    (Pending, Action::Succeed, Succeeded), (Pending, Action::Fail, Failed)];
+const WITHDRAW: &[Transition] = &[(Approving, Action::Approve, Pending), (Approving, Action::Reject, Failed), (WaitBroadcast, Action::Broadcast, Pending),
+    (Pending, Action::Succeed, Succeeded), (Pending, Action::Fail, Failed)];
+const FINISHED: &[Transition] = &[];          //仅用于导入历史数据的类型 不能再改变
+
+pub fn transitions(r#type: &TransferType)-> &'static [Transition] {       //每种交易类型合法的状态变化
+    match r#type {
+        TransferType::Fund | TransferType::NodeFund=> FUND,
+        TransferType::Pay | TransferType::Gas | TransferType::BatchPay=> PAY,
+        TransferType::Withdraw | TransferType::NodeWithdraw=> WITHDRAW,
+        TransferType::AirDrop=> FINISHED,
+    }
+}
+
+pub fn next(r#type: &TransferType, status: &TransferStatus, action: Action)-> Option<TransferStatus> {
+    transitions(r#type).iter().find(|t| t.0 == *status && t.1 == action ).map(|t| t.2.clone() )
+}
+
+#[derive(Clone, Debug)]
+pub struct TransitionError {
+    pub trade: StaticStr,                       //Trade 本身不知道 id 由 TradeManager 填写
+    pub r#type: TransferType,
+    pub status: TransferStatus,
+    pub action: Action,
+}
+
+impl TransitionError {
+    pub fn new(trade: &Trade, action: Action)-> Self {
+        Self{trade: Cow::from(""), r#type: trade.r#type.clone(), status: trade.status.clone(), action}
+    }
+}
+
+impl std::fmt::Display for TransitionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>)-> std::fmt::Result {
+        write!(f, "trade {} {:?} in {:?} can not {:?}", self.trade, self.r#type, self.status, self.action)
+    }
+}
+
+impl std::error::Error for TransitionError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ACTIONS: [Action; 5] = [Action::Approve, Action::Reject, Action::Broadcast, Action::Succeed, Action::Fail];
+    const STATUSES: [TransferStatus; 5] = [Approving, WaitBroadcast, Pending, Succeeded, Failed];
+
+    #[test]
+    fn fund_goes_through_broadcast() {
+        assert_eq!(next(&TransferType::Fund, &WaitBroadcast, Action::Broadcast), Some(Pending));
+        assert_eq!(next(&TransferType::Fund, &Pending, Action::Succeed), Some(Succeeded));
+        assert_eq!(next(&TransferType::Fund, &Pending, Action::Fail), Some(Failed));
+        assert_eq!(next(&TransferType::Fund, &WaitBroadcast, Action::Succeed), None);
+        assert_eq!(next(&TransferType::Fund, &Approving, Action::Approve), None);
+    }
+
+    #[test]
+    fn pay_and_withdraw_need_approval_before_pending() {
+        for r#type in [TransferType::Pay, TransferType::BatchPay, TransferType::Withdraw] {
+            assert_eq!(next(&r#type, &Approving, Action::Approve), Some(Pending));
+            assert_eq!(next(&r#type, &Approving, Action::Reject), Some(Failed));
+            assert_eq!(next(&r#type, &Approving, Action::Succeed), None);
+            assert_eq!(next(&r#type, &Pending, Action::Succeed), Some(Succeeded));
+            assert_eq!(next(&r#type, &Pending, Action::Approve), None);
+        }
+        assert_eq!(next(&TransferType::Withdraw, &WaitBroadcast, Action::Broadcast), Some(Pending));
+        assert_eq!(next(&TransferType::Pay, &WaitBroadcast, Action::Broadcast), None);
+    }
+
+    #[test]
+    fn finished_trades_never_change() {
+        for r#type in [TransferType::Fund, TransferType::Pay, TransferType::Gas, TransferType::Withdraw, TransferType::BatchPay, TransferType::AirDrop] {
+            for action in ACTIONS {
+                assert_eq!(next(&r#type, &Succeeded, action), None);
+                assert_eq!(next(&r#type, &Failed, action), None);
+            }
+        }
+        for status in STATUSES {
+            for action in ACTIONS {
+                assert_eq!(next(&TransferType::AirDrop, &status, action), None);
+            }
+        }
+    }
+
+    #[test]
+    fn each_status_and_action_has_one_target() {
+        for r#type in [TransferType::Fund, TransferType::Pay, TransferType::Withdraw] {
+            let table = transitions(&r#type);
+            for (i, t) in table.iter().enumerate() {
+                assert!(!table[..i].iter().any(|o| o.0 == t.0 && o.1 == t.1 ), "{:?} {:?} {:?} repeated", r#type, t.0, t.1);
+            }
+        }
+    }
+}
@@ -0,0 +1,382 @@
+use anyhow::{Result, anyhow};
+use std::borrow::Cow;
+use std::sync::{Arc, Mutex};
+use once_cell::sync::{Lazy, OnceCell};
+use lockfree_object_pool::LinearObjectPool;
+use redis::{Connection, Commands};
+use super::trade::{StaticStr, Trade};
+
+pub trait TradeStore: Send + Sync {                     //交易的持久化 同一个 asset 一个 store 保存插入顺序
+    fn contains(&self, id: &StaticStr)-> bool;
+    fn insert(&self, id: &StaticStr, t: &Trade)-> bool;
+    fn update(&self, id: &StaticStr, value: &Trade)-> bool;
+    fn get(&self, id: &StaticStr)-> Option<Trade>;
+    fn load_all(&self, f: &mut dyn FnMut(StaticStr, Trade))-> Result<()>;      //按照插入顺序回调
+    fn len(&self)-> usize;                              //交易列表的长度
+    fn is_empty(&self)-> bool {
+        self.len() == 0
+    }
+    fn clean_up(&self);
+    fn repair(&self, _fix: bool)-> Result<Repair> {      //查找旧版本非原子写入留下的孤儿数据 fix 为 false 只报告
+        Ok(Repair::default())
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Repair {
+    pub unlisted: Vec<StaticStr>,                       //有交易内容但是不在列表中 load_all 不会加载 修复时按 create_tick 追加到列表
+    pub missing: Vec<StaticStr>,                        //在列表中但是没有交易内容 修复时从列表中删除
+}
+
+pub trait MetaStore: Send + Sync {                      //交易以外的数据 资产列表等 简单的 blob 和 list
+    fn get(&self, key: &str)-> Option<Vec<u8>>;
+    fn set(&self, key: &str, value: &[u8])-> bool;
+    fn list(&self, key: &str)-> Vec<Vec<u8>>;
+    fn push(&self, key: &str, value: &[u8])-> bool;
+    fn set_at(&self, key: &str, index: usize, value: &[u8])-> bool;
+    fn remove(&self, key: &str);
+}
+
+#[derive(Clone, Debug)]
+pub enum StoreConfig {
+    Redis(String),                                      //redis url
+    Sled(String),                                       //本地目录 单节点部署使用
+    Memory,                                             //仅用于测试 进程退出数据丢失
+}
+
+impl Default for StoreConfig {
+    fn default()-> Self {
+        Self::Redis("redis://127.0.0.1".to_string())
+    }
+}
+
+impl std::str::FromStr for StoreConfig {
+    type Err = anyhow::Error;
+    fn from_str(s: &str)-> Result<Self> {              //redis://... sled:<path> memory
+        if s.starts_with("redis://") || s.starts_with("rediss://") { Ok(Self::Redis(s.to_string())) }
+        else if let Some(path) = s.strip_prefix("sled:") { Ok(Self::Sled(path.to_string())) }
+        else if s == "memory" { Ok(Self::Memory) }
+        else { Err(anyhow!("unknow store {}", s)) }
+    }
+}
+
+static CONFIG: OnceCell<StoreConfig> = OnceCell::new();
+
+fn key(key: &str)-> String {                            //加上配置中的 key_prefix
+    format!("{}{}", super::config::get().key_prefix, key)
+}
+
+pub fn configure(config: StoreConfig)-> Result<()> {           //必须在第一次访问 TRADES 之前调用 否则使用默认的 redis
+    CONFIG.set(config).map_err(|c| anyhow!("store already configured {:?}", c))
+}
+
+pub enum Backend {
+    Redis(Arc<LinearObjectPool<Connection>>),
+    Sled(sled::Db),
+    Memory(Arc<MemoryMeta>),
+}
+
+pub(crate) static BACKEND: Lazy<Backend> = Lazy::new(|| {
+    match CONFIG.get_or_init(StoreConfig::default).clone() {
+        StoreConfig::Redis(url)=> {
+            Backend::Redis(Arc::new(LinearObjectPool::<Connection>::new(move || {
+                let client = redis::Client::open(url.as_str()).map_err(|e| log::error!("{:?}", e) ).unwrap();
+                client.get_connection().unwrap()
+            }, move |_| {})))
+        }
+        StoreConfig::Sled(path)=> Backend::Sled(sled::open(&path).map_err(|e| log::error!("open {} {:?}", path, e) ).unwrap()),
+        StoreConfig::Memory=> Backend::Memory(Arc::new(MemoryMeta::default())),
+    }
+});
+
+type MetaJob = Box<dyn FnOnce(&dyn MetaStore) + Send>;
+
+static META_WRITER: Lazy<Mutex<std::sync::mpsc::Sender<MetaJob>>> = Lazy::new(|| {      //和交易一样 meta 的同步写入在单独的线程按提交顺序执行
+    let (tx, rx) = std::sync::mpsc::channel::<MetaJob>();
+    std::thread::Builder::new().name("store-meta".to_string()).spawn(move || {
+        for job in rx {
+            job(BACKEND.meta());
+        }
+    }).unwrap();
+    Mutex::new(tx)
+});
+
+pub(crate) async fn meta_write<F: FnOnce(&dyn MetaStore)-> bool + Send + 'static>(f: F)-> bool {       //不阻塞 tokio 的线程 等待写入结果
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let job: MetaJob = Box::new(move |meta| { let _ = tx.send(f(meta)); });
+    if META_WRITER.lock().unwrap().send(job).is_err() { return false }
+    rx.await.unwrap_or(false)
+}
+
+impl Backend {
+    pub fn trades(&self, name: StaticStr)-> Box<dyn TradeStore> {
+        match self {
+            Self::Redis(pool)=> Box::new(RedisStore::new(name, pool.clone())),
+            Self::Sled(db)=> Box::new(SledStore::new(name, db).unwrap()),
+            Self::Memory(_)=> Box::new(MemoryStore::default()),
+        }
+    }
+    pub fn meta(&self)-> &dyn MetaStore {
+        match self {
+            Self::Redis(pool)=> pool.as_ref(),
+            Self::Sled(db)=> db,
+            Self::Memory(meta)=> meta.as_ref(),
+        }
+    }
+}
+
+pub struct RedisStore {
+    list_key: StaticStr,
+    trades_key: StaticStr,
+    pool: Arc<LinearObjectPool<Connection>>,
+}
+
+impl RedisStore {
+    pub fn new(name: StaticStr, pool: Arc<LinearObjectPool<Connection>>)-> Self {
+        let list_key = Cow::from(key(&format!("@list::{}", name)));
+        let trades_key = Cow::from(key(&format!("@trades::{}", name)));
+        Self{list_key, trades_key, pool}
+    }
+}
+
+impl TradeStore for RedisStore {
+    fn clean_up(&self) {
+        let mut c = self.pool.pull();
+        let _ = c.del::<&str, bool>(self.list_key.as_ref());
+        let _ = c.del::<&str, bool>(self.trades_key.as_ref());
+    }
+
+    fn contains(&self, id: &StaticStr)-> bool {
+        let mut c = self.pool.pull();
+        c.hexists(self.trades_key.as_ref(), id).unwrap_or(false)
+    }
+
+    fn insert(&self, id: &StaticStr, t: &Trade)-> bool {         //MULTI/EXEC 保证 hash 和 list 同时写入
+        let mut c = self.pool.pull();
+        redis::pipe().atomic().hset(self.trades_key.as_ref(), id.as_ref(), rmp_serde::to_vec(&t).unwrap()).ignore()
+            .rpush(self.list_key.as_ref(), id.as_ref()).ignore().query::<()>(&mut *c).is_ok()
+    }
+
+    fn update(&self, id: &StaticStr, value: &Trade)-> bool {       //内存保证多个线程不会同时更新
+        let mut c = self.pool.pull();
+        c.hset::<&str, &str, Vec<u8>, bool>(self.trades_key.as_ref(), id, rmp_serde::to_vec(&value).unwrap()).is_ok()
+    }
+
+    fn get(&self, id: &StaticStr)-> Option<Trade> {
+        let mut c = self.pool.pull();
+        c.hget::<&str, &str, Vec<u8>>(self.trades_key.as_ref(), id).ok().and_then(|buf| rmp_serde::from_slice::<Trade>(&buf).ok() )
+    }
+
+    fn len(&self)-> usize {
+        self.pool.pull().llen(self.list_key.as_ref()).unwrap_or(0)
+    }
+
+    fn load_all(&self, f: &mut dyn FnMut(StaticStr, Trade))-> Result<()> {
+        let mut c = self.pool.pull();
+        let keys: Vec<String> = c.lrange(self.list_key.as_ref(), 0, -1)?;
+        log::info!("{} len {}", self.list_key, keys.len());
+        let kvs: std::collections::BTreeMap<String, Vec<u8>> = c.hgetall(self.trades_key.as_ref())?;
+        log::info!("{} len {}", self.trades_key, kvs.len());
+        for key in keys {
+            if let Some(trade) = kvs.get(&key).and_then(|buf| rmp_serde::from_slice::<Trade>(buf).ok() ) {
+                f(Cow::from(key), trade);
+            }
+        }
+        Ok(())
+    }
+
+    fn repair(&self, fix: bool)-> Result<Repair> {      //需要在没有写入的时候执行
+        let mut c = self.pool.pull();
+        let list: Vec<String> = c.lrange(self.list_key.as_ref(), 0, -1)?;
+        let ids: Vec<String> = c.hkeys(self.trades_key.as_ref())?;
+        let listed: std::collections::HashSet<&String> = list.iter().collect();
+        let stored: std::collections::HashSet<&String> = ids.iter().collect();
+        let mut unlisted = Vec::new();
+        for id in ids.iter().filter(|id| !listed.contains(id) ) {
+            let tick = c.hget::<&str, &str, Vec<u8>>(self.trades_key.as_ref(), id).ok().and_then(|buf| rmp_serde::from_slice::<Trade>(&buf).ok() ).map(|t| t.create_tick ).unwrap_or(0);
+            unlisted.push((tick, Cow::from(id.clone())));
+        }
+        unlisted.sort();
+        let mut missing: Vec<StaticStr> = list.iter().filter(|id| !stored.contains(id) ).map(|id| Cow::from(id.clone()) ).collect();
+        missing.dedup();
+        if fix {
+            for (_, id) in unlisted.iter() {
+                c.rpush::<&str, &str, usize>(self.list_key.as_ref(), id)?;
+            }
+            for id in missing.iter() {
+                c.lrem::<&str, &str, usize>(self.list_key.as_ref(), 0, id)?;
+            }
+        }
+        Ok(Repair{unlisted: unlisted.into_iter().map(|(_, id)| id ).collect(), missing})
+    }
+}
+
+impl MetaStore for LinearObjectPool<Connection> {
+    fn get(&self, key: &str)-> Option<Vec<u8>> {
+        self.pull().get::<String, Option<Vec<u8>>>(self::key(key)).ok().flatten()
+    }
+    fn set(&self, key: &str, value: &[u8])-> bool {
+        self.pull().set::<String, &[u8], ()>(self::key(key), value).is_ok()
+    }
+    fn list(&self, key: &str)-> Vec<Vec<u8>> {
+        self.pull().lrange(self::key(key), 0, -1).unwrap_or_default()
+    }
+    fn push(&self, key: &str, value: &[u8])-> bool {
+        self.pull().rpush::<String, &[u8], usize>(self::key(key), value).is_ok()
+    }
+    fn set_at(&self, key: &str, index: usize, value: &[u8])-> bool {
+        self.pull().lset::<String, &[u8], ()>(self::key(key), index as isize, value).is_ok()
+    }
+    fn remove(&self, key: &str) {
+        let _ = self.pull().del::<String, bool>(self::key(key));
+    }
+}
+
+pub struct SledStore {                                  //@list:: 保存 generate_id -> id 的顺序 @trades:: 保存 id -> trade
+    list: sled::Tree,
+    trades: sled::Tree,
+}
+
+impl SledStore {
+    pub fn new(name: StaticStr, db: &sled::Db)-> Result<Self> {
+        Ok(Self{list: db.open_tree(key(&format!("@list::{}", name)))?, trades: db.open_tree(key(&format!("@trades::{}", name)))?})
+    }
+}
+
+impl TradeStore for SledStore {
+    fn clean_up(&self) {
+        let _ = self.list.clear();
+        let _ = self.trades.clear();
+        let _ = self.trades.flush();
+    }
+
+    fn contains(&self, id: &StaticStr)-> bool {
+        self.trades.contains_key(id.as_bytes()).unwrap_or(false)
+    }
+
+    fn insert(&self, id: &StaticStr, t: &Trade)-> bool {
+        use sled::Transactional;
+        let value = rmp_serde::to_vec(&t).unwrap();
+        (&self.trades, &self.list).transaction(|(trades, list)| {
+            trades.insert(id.as_bytes(), value.as_slice())?;
+            list.insert(&list.generate_id()?.to_be_bytes(), id.as_bytes())?;
+            Ok::<(), sled::transaction::ConflictableTransactionError<()>>(())
+        }).is_ok() && self.trades.flush().is_ok()          //sled 默认异步落盘 返回之前 flush 否则进程退出会丢失
+    }
+
+    fn update(&self, id: &StaticStr, value: &Trade)-> bool {
+        self.trades.insert(id.as_bytes(), rmp_serde::to_vec(&value).unwrap()).is_ok() && self.trades.flush().is_ok()
+    }
+
+    fn get(&self, id: &StaticStr)-> Option<Trade> {
+        self.trades.get(id.as_bytes()).ok().flatten().and_then(|buf| rmp_serde::from_slice::<Trade>(&buf).ok() )
+    }
+
+    fn len(&self)-> usize {
+        self.list.len()
+    }
+
+    fn load_all(&self, f: &mut dyn FnMut(StaticStr, Trade))-> Result<()> {
+        log::info!("sled list len {} trades len {}", self.list.len(), self.trades.len());
+        for item in self.list.iter() {
+            let (_, id) = item?;
+            if let Some(trade) = self.trades.get(&id)?.and_then(|buf| rmp_serde::from_slice::<Trade>(&buf).ok() ) {
+                f(Cow::from(String::from_utf8_lossy(&id).into_owned()), trade);
+            }
+        }
+        Ok(())
+    }
+}
+
+static SLED_LIST_LOCK: Mutex<()> = Mutex::new(());
+
+impl MetaStore for sled::Db {                           //list 使用单独的 tree key 是大端的下标
+    fn get(&self, key: &str)-> Option<Vec<u8>> {
+        sled::Tree::get(self, self::key(key)).ok().flatten().map(|v| v.to_vec() )
+    }
+    fn set(&self, key: &str, value: &[u8])-> bool {
+        self.insert(self::key(key), value).is_ok() && self.flush().is_ok()
+    }
+    fn list(&self, key: &str)-> Vec<Vec<u8>> {
+        self.open_tree(self::key(&format!("@meta::{}", key))).map(|tree| tree.iter().values().filter_map(|v| v.ok().map(|v| v.to_vec()) ).collect() ).unwrap_or_default()
+    }
+    fn push(&self, key: &str, value: &[u8])-> bool {
+        let _lock = SLED_LIST_LOCK.lock().unwrap();
+        self.open_tree(self::key(&format!("@meta::{}", key))).and_then(|tree| tree.insert((tree.len() as u64).to_be_bytes(), value) ).and_then(|_| self.flush() ).is_ok()
+    }
+    fn set_at(&self, key: &str, index: usize, value: &[u8])-> bool {
+        self.open_tree(self::key(&format!("@meta::{}", key))).and_then(|tree| tree.insert((index as u64).to_be_bytes(), value) ).and_then(|_| self.flush() ).is_ok()
+    }
+    fn remove(&self, key: &str) {
+        let _ = sled::Tree::remove(self, self::key(key));
+        let _ = self.drop_tree(self::key(&format!("@meta::{}", key)));
+        let _ = self.flush();
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    inner: Mutex<(Vec<StaticStr>, std::collections::HashMap<StaticStr, Trade>)>,
+}
+
+impl TradeStore for MemoryStore {
+    fn clean_up(&self) {
+        *self.inner.lock().unwrap() = Default::default();
+    }
+    fn contains(&self, id: &StaticStr)-> bool {
+        self.inner.lock().unwrap().1.contains_key(id)
+    }
+    fn insert(&self, id: &StaticStr, t: &Trade)-> bool {
+        let mut inner = self.inner.lock().unwrap();
+        inner.0.push(id.clone());
+        inner.1.insert(id.clone(), t.clone());
+        true
+    }
+    fn update(&self, id: &StaticStr, value: &Trade)-> bool {
+        self.inner.lock().unwrap().1.insert(id.clone(), value.clone());
+        true
+    }
+    fn get(&self, id: &StaticStr)-> Option<Trade> {
+        self.inner.lock().unwrap().1.get(id).cloned()
+    }
+    fn len(&self)-> usize {
+        self.inner.lock().unwrap().0.len()
+    }
+    fn load_all(&self, f: &mut dyn FnMut(StaticStr, Trade))-> Result<()> {
+        let (ids, trades) = self.inner.lock().unwrap().clone();
+        for id in ids {
+            if let Some(trade) = trades.get(&id) { f(id, trade.clone()); }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryMeta {
+    blobs: Mutex<std::collections::HashMap<String, Vec<u8>>>,
+    lists: Mutex<std::collections::HashMap<String, Vec<Vec<u8>>>>,
+}
+
+impl MetaStore for MemoryMeta {
+    fn get(&self, key: &str)-> Option<Vec<u8>> {
+        self.blobs.lock().unwrap().get(key).cloned()
+    }
+    fn set(&self, key: &str, value: &[u8])-> bool {
+        self.blobs.lock().unwrap().insert(key.to_string(), value.to_vec());
+        true
+    }
+    fn list(&self, key: &str)-> Vec<Vec<u8>> {
+        self.lists.lock().unwrap().get(key).cloned().unwrap_or_default()
+    }
+    fn push(&self, key: &str, value: &[u8])-> bool {
+        self.lists.lock().unwrap().entry(key.to_string()).or_default().push(value.to_vec());
+        true
+    }
+    fn set_at(&self, key: &str, index: usize, value: &[u8])-> bool {
+        self.lists.lock().unwrap().get_mut(key).and_then(|list| list.get_mut(index) ).map(|v| *v = value.to_vec() ).is_some()
+    }
+    fn remove(&self, key: &str) {
+        self.blobs.lock().unwrap().remove(key);
+        self.lists.lock().unwrap().remove(key);
+    }
+}
@@ -1,11 +1,10 @@
 use scc::{HashMap, HashSet};
-use anyhow::{Result, anyhow};
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 
 pub type StaticStr = Cow<'static, str>;
 
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum TransferType {
     NodeFund,
     Fund,
@@ -14,6 +13,7 @@ pub enum TransferType {
     Pay,
     Gas,
     AirDrop,                                    //空投类型 仅作为历史需要保留 没有来源的入账 
+    BatchPay,                                   //一次锁定 多个接收方 接收方和金额在 outputs 中
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
@@ -25,7 +25,7 @@ pub enum TransferStatus {
     Failed,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct GasInfo {
     pub asset: u32,
     pub amount: u64,
@@ -38,7 +38,15 @@ impl GasInfo {
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Review {                             //审核记录 谁在什么时候通过或者拒绝
+    pub operator: StaticStr,
+    pub approved: bool,
+    pub reason: Option<StaticStr>,
+    pub tick: i64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct Trade {
     pub r#type: TransferType,
     pub status: TransferStatus,
@@ -52,26 +60,49 @@ pub struct Trade {
     pub to_node: Option<StaticStr>,
     pub channel: Option<StaticStr>,
     pub hash: StaticStr,
+    #[serde(default)]
+    pub review: Option<Review>,                 //新增字段放在最后 兼容已经保存的数据
+    #[serde(default)]
+    pub outputs: Vec<(StaticStr, u64)>,         //BatchPay 的 (接收方, 金额) 合计等于 amount
 }
 
-impl Trade {
-    pub fn start(&mut self)-> bool {
-        if self.status == TransferStatus::WaitBroadcast {
-            self.status = TransferStatus::Pending;
-            true
-        } else { false }
-    }
-    pub fn modify(&mut self, success: bool)-> bool {
-        if self.status == TransferStatus::Pending { 
-            self.status = if success { TransferStatus::Succeeded } else { TransferStatus::Failed };
-            self.update_tick = chrono::Utc::now().timestamp();
-            true
-        } else { false }
-    }
-    pub fn success(&mut self)-> bool {
+impl Trade {                                    //所有的状态变化都通过 state::transitions 检查
+    pub fn transit(&mut self, action: Action)-> Result<(), TransitionError> {
+        let status = state::next(&self.r#type, &self.status, action).ok_or(TransitionError::new(self, action))?;
+        self.status = status;
+        self.update_tick = chrono::Utc::now().timestamp();
+        Ok(())
+    }
+    pub fn start(&mut self)-> Result<(), TransitionError> {
+        self.transit(Action::Broadcast)
+    }
+    pub fn broadcast(&mut self, hash: StaticStr)-> Result<(), TransitionError> {          //记录链上的 hash 同时进入 Pending
+        self.start()?;
+        self.hash = hash;
+        Ok(())
+    }
+    pub fn modify(&mut self, success: bool)-> Result<(), TransitionError> {
+        self.transit(if success { Action::Succeed } else { Action::Fail })
+    }
+    pub fn approve(&mut self, operator: StaticStr)-> Result<(), TransitionError> {         //审核通过 进入 Pending 等待完成
+        self.transit(Action::Approve)?;
+        self.review = Some(Review{operator, approved: true, reason: None, tick: self.update_tick});
+        Ok(())
+    }
+    pub fn reject(&mut self, operator: StaticStr, reason: StaticStr)-> Result<(), TransitionError> {
+        self.transit(Action::Reject)?;
+        self.review = Some(Review{operator, approved: false, reason: Some(reason), tick: self.update_tick});
+        Ok(())
+    }
+    pub fn expire(&mut self, reason: StaticStr)-> Result<(), TransitionError> {     //超时自动失败 原因记录在 review 中
+        self.transit(Action::Fail)?;
+        self.review = Some(Review{operator: Cow::from(super::expiry::OPERATOR), approved: false, reason: Some(reason), tick: self.update_tick});
+        Ok(())
+    }
+    pub fn success(&mut self)-> Result<(), TransitionError> {
         self.modify(true)
     }
-    pub fn fail(&mut self)-> bool {
+    pub fn fail(&mut self)-> Result<(), TransitionError> {
         self.modify(false)
     }
 }
@@ -79,136 +110,176 @@ impl Trade {
 impl Trade {
     pub fn pay(from: StaticStr, to: StaticStr, amount: u64, gas: Vec<GasInfo>, hash: StaticStr)-> Self {
         Self{r#type: TransferType::Pay, status: TransferStatus::Pending, create_tick: chrono::Utc::now().timestamp(), update_tick: 0,
-            amount, gas, from, to, hash, from_node: None, to_node: None, channel: None}
+            amount, gas, from, to, hash, from_node: None, to_node: None, channel: None, review: None, outputs: Vec::new()}
     }
     pub fn fund(from: StaticStr, to: StaticStr, amount: u64, gas: Vec<GasInfo>, hash: StaticStr)-> Self {  //充值订单 没有手续费 目的地是平台地址
         Self{r#type: TransferType::Fund, status: TransferStatus::WaitBroadcast, create_tick: chrono::Utc::now().timestamp(), update_tick: 0,
-            amount, gas, from, to, hash, from_node: None, to_node: None, channel: None}
+            amount, gas, from, to, hash, from_node: None, to_node: None, channel: None, review: None, outputs: Vec::new()}
     }
     pub fn withdraw(from: StaticStr, to: StaticStr, amount: u64, gas: Vec<GasInfo>, hash: StaticStr)-> Self {   //生成 withdraw 交易 之前是需要分别生成 交易 rna 手续费 其他手续费三条订单记录 现在放在一条订单里面
         Self{r#type: TransferType::Withdraw, status: TransferStatus::Pending, create_tick: chrono::Utc::now().timestamp(), update_tick: 0,
-            amount, gas, from, to, hash, from_node: None, to_node: None, channel: None}
+            amount, gas, from, to, hash, from_node: None, to_node: None, channel: None, review: None, outputs: Vec::new()}
+    }
+    pub fn batch_pay(from: StaticStr, outputs: Vec<(StaticStr, u64)>, gas: Vec<GasInfo>, hash: StaticStr)-> Option<Self> {     //金额合计溢出返回 None to 为空
+        let amount = outputs.iter().try_fold(0u64, |sum, o| sum.checked_add(o.1) )?;
+        Some(Self{r#type: TransferType::BatchPay, status: TransferStatus::Pending, create_tick: chrono::Utc::now().timestamp(), update_tick: 0,
+            amount, gas, from, to: Cow::from(""), hash, from_node: None, to_node: None, channel: None, review: None, outputs})
+    }
+    pub fn credits(&self)-> Vec<(StaticStr, u64)> {         //成功之后每个接收方入账的金额 提现到自己的地址入账到 WITHDRAW_ADDR
+        if self.r#type == TransferType::BatchPay { self.outputs.clone() }
+        else if self.r#type == TransferType::Withdraw && self.from == self.to { vec![(WITHDRAW_ADDR.clone(), self.amount)] }
+        else { vec![(self.to.clone(), self.amount)] }
+    }
+    pub fn recipients(&self)-> Vec<StaticStr> {             //交易列表中需要记录这笔交易的接收方 不重复
+        let mut recipients: Vec<StaticStr> = if self.r#type == TransferType::BatchPay { self.outputs.iter().map(|o| o.0.clone() ).collect() } else { vec![self.to.clone()] };
+        recipients.sort();
+        recipients.dedup();
+        recipients
+    }
+    pub fn counterparties(&self)-> Vec<StaticStr> {         //除了 from 以外需要记录这笔交易的账户 接收方和 gas 接收方
+        let mut accounts = self.recipients();
+        accounts.extend(self.gas.iter().map(|g| g.to.clone() ));
+        accounts.sort();
+        accounts.dedup();
+        accounts.retain(|a| *a != self.from );
+        accounts
+    }
+    pub fn involves(&self, account: &str)-> bool {
+        self.from == account || self.to == account || self.outputs.iter().any(|o| o.0 == account )
     }
     pub(crate) fn airdrop(to: StaticStr, amount: u64)-> Self {  //仅用于导入历史数据
         Self{r#type: TransferType::AirDrop, status: TransferStatus::Succeeded, create_tick: chrono::Utc::now().timestamp(), update_tick: 0,
-            amount, gas: Vec::new(), from: Cow::from(""), to, hash: Cow::from(""), from_node: None, to_node: None, channel: None}
+            amount, gas: Vec::new(), from: Cow::from(""), to, hash: Cow::from(""), from_node: None, to_node: None, channel: None, review: None, outputs: Vec::new()}
     }
     pub(crate) fn gas(from: StaticStr, to: StaticStr, amount: u64)-> Self {      //仅用于导入历史数据
         Self{r#type: TransferType::Gas, status: TransferStatus::Succeeded, create_tick: chrono::Utc::now().timestamp(), update_tick: 0,
-            amount, gas: Vec::new(), from, to, hash: Cow::from(""), from_node: None, to_node: None, channel: None}
+            amount, gas: Vec::new(), from, to, hash: Cow::from(""), from_node: None, to_node: None, channel: None, review: None, outputs: Vec::new()}
     }
 }
 
-static REDIS_URL: &'static str = "redis://127.0.0.1";
 use once_cell::sync::Lazy;
-use std::sync::Arc;
-use lockfree_object_pool::LinearObjectPool;
-use redis::{Connection, Commands};
+use std::sync::{mpsc, Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::oneshot;
+use super::asset::ASSETS;
+use super::config;
+use super::store::{TradeStore, BACKEND};
+use super::state::{self, Action, TransitionError};
+use super::error::{LedgerError, LedgerResult};
+use super::events::{self, LedgerEvent};
 
-pub const ASSET_NUM: usize = 8;             //暂时支持最多8个资产
-pub const ASSET_NAMES: [&'static str; ASSET_NUM] = ["BTC_ASSET_ID", "rgb:7Yjbbk!p-Dl4GOJG-Z2ct!BU-yJ2Ji8I-z13MdSL-QAklonM",
-    "rgb:o2PKHzYo-YVviDw7-LKUJAPH-ARrmVW0-aQndBsH-WJJ2540", "rgb:P1Jy$7jt-5ezm74W-SSlIuCW-axO9dfV-$9TPimE-gex6l$8",
-    "rgb:!BmcPbfz-BpQWa0Q-qsmVlp0-VV12tvx-I2WkNz3-D!dGFmw", "rgb:RspPWEW9-mzuSNHQ-dGCb054-bLjHPYi-$I9$Ih2-Fy9vxFU",
-    "rgb:VNyUso5w-6rx1FoB-kODxlFs-$Ej0BJP-aIsyDMs-acdufQs", "_reserved_2"];
+pub static WITHDRAW_ADDR: Lazy<StaticStr> = Lazy::new(|| Cow::from(config::get().accounts.withdraw.clone()) );      //来自配置 config::init 之后才能访问
+pub static FUND_ADDR: Lazy<StaticStr> = Lazy::new(|| Cow::from(config::get().accounts.fund.clone()) );              //充值的来源 只出现在 journal 里面
+pub static GAS_RECEIVE_ADDR: Lazy<StaticStr> = Lazy::new(|| Cow::from(config::get().accounts.gas_receive.clone()) );
 
-pub static WITHDRAW_ADDR: &'static str = "use_to_receive_withdraw_asset";
-pub static GAS_RECEIVE_ADDR: &'static str = "bc1qljz0dldnml3y897n68jxtnycyy62szlpn2mh9a";
-
-pub static ASSET_JERRY: u32 = 5;
-pub static ASSET_RNA: u32 = 2;
-pub static ASSET_BTC: u32 = 0;
+pub static ASSET_JERRY: Lazy<u32> = Lazy::new(|| config::get().system_assets.jerry );
+pub static ASSET_RNA: Lazy<u32> = Lazy::new(|| config::get().system_assets.rna );
+pub static ASSET_BTC: Lazy<u32> = Lazy::new(|| config::get().system_assets.btc );
 
+pub static TRADES: Lazy<RwLock<Vec<Arc<TradeManager>>>> = Lazy::new(|| {        //下标就是 asset id 由 ASSETS 决定
+    RwLock::new(ASSETS.list().into_iter().enumerate().map(|(asset, info)| Arc::new(TradeManager::new(asset as u32, BACKEND.trades(info.name))) ).collect())
+});
 
-pub static TRADES: Lazy<Vec<Arc<TradeManager>>> = Lazy::new(|| {
-    let pool = Arc::new(LinearObjectPool::<Connection>::new(move || {
-        let client = redis::Client::open(REDIS_URL).map_err(|e| log::error!("{:?}", e) ).unwrap();
-        client.get_connection().unwrap()
-    }, move |_| {}));
+pub fn manager(asset: u32)-> LedgerResult<Arc<TradeManager>> {
+    TRADES.read().unwrap().get(asset as usize).cloned().ok_or(LedgerError::UnknownAsset(Cow::from(asset.to_string())))
+}
 
-    let mut trades = Vec::new();
-    for name in ASSET_NAMES {
-        trades.push(Arc::new(TradeManager::new(pool.clone(), Cow::from(name))) );
-    }
-    trades
-});
+pub fn managers()-> Vec<Arc<TradeManager>> {
+    TRADES.read().unwrap().clone()
+}
 
 pub fn update_trade<F: FnMut(&mut Trade)>(id: &StaticStr, mut f: F)-> bool {
-    for asset in 0..ASSET_NUM {
-        if let Some(mut trade) = TRADES[asset].store.get(id) {
+    for trades in managers() {
+        if let Some(mut trade) = trades.store.get(id) {
             f(&mut trade);
-            return TRADES[asset].store.update(id, &trade);
+            return trades.store.update(id, &trade);
         }
     }
     false
 }
 
-pub struct RedisStore {
-    list_key: StaticStr,
-    trades_key: StaticStr,
-    pool: Arc<LinearObjectPool::<Connection>>,
-}
-
-impl RedisStore {
-    pub fn new(name: StaticStr, pool: Arc<LinearObjectPool::<Connection>>)-> Self {
-        let list_key = Cow::from(format!("@list::{}", name));
-        let trades_key = Cow::from(format!("@trades::{}", name));
-        Self{list_key, trades_key, pool}
-    }
+pub static HASHES: Lazy<HashMap<StaticStr, Vec<(u32, StaticStr)>>> = Lazy::new(HashMap::default);     //链上 hash -> (asset, trade_id)
 
-    pub(crate) fn clean_up(&self) {
-        let mut c = self.pool.pull();
-        let _ = c.del::<&str, bool>(self.list_key.as_ref());
-        let _ = c.del::<&str, bool>(self.trades_key.as_ref());
-    }
+fn index_hash(hash: &StaticStr, asset: u32, trade_id: &StaticStr) {
+    if hash.is_empty() { return }
+    HASHES.entry(hash.clone()).or_default().get_mut().push((asset, trade_id.clone()));
+}
 
-    pub(crate) fn contains(&self, id: &StaticStr)-> bool {
-        let mut c = self.pool.pull();
-        c.hexists(self.trades_key.as_ref(), id).unwrap_or(false)
-    }
+fn unindex_hash(hash: &StaticStr, asset: u32, trade_id: &StaticStr) {
+    if hash.is_empty() { return }
+    let _ = HASHES.remove_if(hash, |ids| {
+        ids.retain(|id| id.0 != asset || id.1 != *trade_id );
+        ids.is_empty()
+    });
+}
 
-    pub(crate) fn insert(&self, id: &StaticStr, t: &Trade)-> bool {
-        let mut c = self.pool.pull();
-        if c.hset::<&str, &str, Vec<u8>, bool>(self.trades_key.as_ref(), id, rmp_serde::to_vec(&t).unwrap()).is_ok() {
-            c.rpush(self.list_key.as_ref(), id).unwrap_or(false)
-        } else {
-            false
-        }
-    }
+pub struct TradeManager {
+    pub asset: u32,
+    pub trades: HashMap<StaticStr, Trade>,                      //内存中保存的所有交易的列表
+    pub approving: HashSet<StaticStr>,
+    pub waiting: HashSet<StaticStr>,                            //WaitBroadcast 状态 等待签名服务广播
+    reserved: HashSet<StaticStr>,                               //正在创建的交易 id 保存完成之前占用
+    pub(crate) listed: AtomicUsize,                             //交易列表的条目数量 包括重复的 id 和 load_all 的计数一致
+    pub store: Arc<dyn TradeStore>,
+    writer: mpsc::Sender<(Write, oneshot::Sender<bool>)>,       //所有的写入按照提交的顺序在单独的线程执行
+}
 
-    pub(crate) fn update(&self, id: &StaticStr, value: &Trade)-> bool {       //内存保证多个线程不会同时更新
-        let mut c = self.pool.pull();
-        c.hset::<&str, &str, Vec<u8>, bool>(self.trades_key.as_ref(), id, rmp_serde::to_vec(&value).unwrap()).is_ok()
-    }
+pub(crate) struct Reserved {
+    manager: Arc<TradeManager>,
+    trade_id: StaticStr,
+}
 
-    pub(crate) fn get(&self, id: &StaticStr)-> Option<Trade> {
-        let mut c = self.pool.pull();
-        c.hget::<&str, &str, Vec<u8>>(self.trades_key.as_ref(), id).ok().and_then(|buf| rmp_serde::from_slice::<Trade>(&buf).ok() )
+impl Drop for Reserved {                        //insert 之后 trades 中已经有这个 id 再释放
+    fn drop(&mut self) {
+        let _ = self.manager.reserved.remove(&self.trade_id);
     }
+}
 
-    pub(crate) fn load_all<F: FnMut(StaticStr, Trade)>(&self, mut f: F)-> Result<()> {
-        let mut c = self.pool.pull();
-        let keys: Vec<String> = c.lrange(self.list_key.as_ref(), 0, -1)?;
-        log::info!("{} len {}", self.list_key, keys.len());
-        let kvs: std::collections::BTreeMap<String, Vec<u8>> = c.hgetall(&self.trades_key)?;
-        log::info!("{} len {}", self.trades_key, kvs.len());
-        for key in keys {
-            if let Some(trade) = kvs.get(&key).and_then(|buf| rmp_serde::from_slice::<Trade>(&buf).ok() ) {
-                f(Cow::from(key), trade);    
-            }
-        }
-        Ok(())
-    }
+enum Write {
+    Insert(StaticStr, Trade),
+    Update(StaticStr, Trade),
 }
 
-pub struct TradeManager {
-    pub trades: HashMap<StaticStr, Trade>,                      //内存中保存的所有交易的列表
-    pub approving: HashSet<StaticStr>,
-    pub store: RedisStore,
+fn spawn_writer(asset: u32, store: Arc<dyn TradeStore>)-> mpsc::Sender<(Write, oneshot::Sender<bool>)> {      //store 的调用是同步的网络请求 不能放在 tokio 的线程里面
+    let (tx, rx) = mpsc::channel::<(Write, oneshot::Sender<bool>)>();
+    std::thread::Builder::new().name(format!("store-{}", asset)).spawn(move || {
+        for (write, ack) in rx {
+            let ok = match &write {
+                Write::Insert(id, trade)=> store.insert(id, trade),
+                Write::Update(id, trade)=> store.update(id, trade),
+            };
+            let _ = ack.send(ok);
+        }
+    }).unwrap();
+    tx
 }
 
 impl TradeManager {
-    pub fn new(pool: Arc<LinearObjectPool::<Connection>>, name: StaticStr)-> Self {
-        Self{trades: HashMap::default(), approving: HashSet::default(), store: RedisStore::new( name, pool)}
+    pub fn new(asset: u32, store: Box<dyn TradeStore>)-> Self {
+        let store: Arc<dyn TradeStore> = Arc::from(store);
+        let writer = spawn_writer(asset, store.clone());
+        Self{asset, trades: HashMap::default(), approving: HashSet::default(), waiting: HashSet::default(), reserved: HashSet::default(), listed: AtomicUsize::new(0), store, writer}
+    }
+    fn write(&self, write: Write)-> oneshot::Receiver<bool> {        //只是放入队列 不会阻塞 可以在持有 bucket 锁的时候调用
+        let (tx, rx) = oneshot::channel();
+        let _ = self.writer.send((write, tx));                         //线程退出时 rx 返回错误
+        rx
+    }
+    fn reindex(&self, trade_id: &StaticStr, from: &Trade, to: &Trade) {       //维护 approving waiting 以及 hash 索引
+        if from.status == TransferStatus::Approving && to.status != TransferStatus::Approving {
+            let _ = self.approving.remove(trade_id);
+        } else if from.status != TransferStatus::Approving && to.status == TransferStatus::Approving {
+            let _ = self.approving.insert(trade_id.clone());
+        }
+        if from.status == TransferStatus::WaitBroadcast && to.status != TransferStatus::WaitBroadcast {
+            let _ = self.waiting.remove(trade_id);
+        } else if from.status != TransferStatus::WaitBroadcast && to.status == TransferStatus::WaitBroadcast {
+            let _ = self.waiting.insert(trade_id.clone());
+        }
+        if from.hash != to.hash {
+            unindex_hash(&from.hash, self.asset, trade_id);
+            index_hash(&to.hash, self.asset, trade_id);
+        }
     }
     pub async fn trade(&self, id: &StaticStr)-> Option<Trade> {
         self.trades.get_async(id).await.map(|t| t.clone() )
@@ -217,29 +288,107 @@ impl TradeManager {
     pub async fn contains(&self, trade_id: &StaticStr)-> bool {
         self.trades.contains_async(trade_id).await
     }
-    pub(crate) async fn add_trade(&self, trade_id: StaticStr, trade: Trade) {
-        if trade.status == TransferStatus::Approving {
-            self.approving.insert(trade_id.clone()).unwrap();
+    pub(crate) async fn reserve(self: &Arc<Self>, trade_id: &StaticStr)-> LedgerResult<Reserved> {      //先占用再检查 相同 id 的并发请求只有一个成功
+        if self.reserved.insert_async(trade_id.clone()).await.is_err() { return Err(LedgerError::DuplicateTrade(trade_id.clone())); }
+        let reserved = Reserved{manager: self.clone(), trade_id: trade_id.clone()};
+        if self.contains(trade_id).await { return Err(LedgerError::DuplicateTrade(trade_id.clone())); }
+        Ok(reserved)
+    }
+    pub(crate) async fn add_trade(&self, trade_id: StaticStr, trade: Trade)-> bool {     //id 已经存在时不修改 返回 false
+        let (status, hash) = (trade.status.clone(), trade.hash.clone());
+        if self.trades.insert_async(trade_id.clone(), trade).await.is_err() { return false }
+        if status == TransferStatus::Approving {
+            let _ = self.approving.insert(trade_id.clone());
+        } else if status == TransferStatus::WaitBroadcast {
+            let _ = self.waiting.insert(trade_id.clone());
         }
-        let _ = self.trades.insert_async(trade_id, trade).await;
+        index_hash(&hash, self.asset, &trade_id);
+        true
     }
-    pub async fn insert(&self, trade_id: StaticStr, trade: Trade)-> Result<()> {
-        if self.store.insert(&trade_id, &trade) {
-            self.add_trade(trade_id, trade).await;
+    pub async fn insert(&self, trade_id: StaticStr, trade: Trade)-> LedgerResult<()> {       //调用方需要先 reserve id 已经存在时失败
+        if self.contains(&trade_id).await { return Err(LedgerError::DuplicateTrade(trade_id)); }
+        if !self.write(Write::Insert(trade_id.clone(), trade.clone())).await.unwrap_or(false) { return Err(LedgerError::StorageFailure(format!("insert trade {}", trade_id))); }
+        self.listed.fetch_add(1, Ordering::SeqCst);
+        if !self.add_trade(trade_id.clone(), trade.clone()).await {
+            log::error!("trade {} inserted concurrently", trade_id);
+            return Err(LedgerError::DuplicateTrade(trade_id));
         }
+        events::emit(LedgerEvent::TradeCreated{asset: self.asset, trade_id, trade});
         Ok(())
     }
-    pub async fn update<F: Fn(Trade)-> Option<Trade>>(&self, trade_id: StaticStr, f: F)-> Option<Trade> {
-        self.trades.update_async(&trade_id, |k, v| {
-            if let Some(updated) = f(v.clone()) {
-                if self.store.update(&trade_id, &updated) {
-                    if v.status == TransferStatus::Approving && updated.status != TransferStatus::Approving {
-                        let _ = self.approving.remove(k);
-                    }
-                    return std::mem::replace(v, updated);
-                }
+    pub async fn update<F: Fn(&mut Trade)-> Result<(), TransitionError>>(&self, trade_id: &StaticStr, f: F)-> LedgerResult<Trade> {      //返回更新前的交易
+        let (old, updated, ack) = self.trades.update_async(trade_id, |k, v| {
+            let mut updated = v.clone();
+            f(&mut updated).map_err(|mut e| { e.trade = k.clone(); e })?;
+            let ack = self.write(Write::Update(k.clone(), updated.clone()));     //在锁内入队 同一笔交易的写入顺序和内存一致
+            self.reindex(k, v, &updated);
+            Ok::<_, LedgerError>((std::mem::replace(v, updated.clone()), updated, ack))
+        }).await.unwrap_or_else(|| Err(LedgerError::UnknownTrade(trade_id.clone())))?;
+        if ack.await.unwrap_or(false) {                                 //等待写入的时候不持有锁
+            if old.status != updated.status {
+                events::emit(LedgerEvent::StatusChanged{asset: self.asset, trade_id: trade_id.clone(), from: old.status.clone(), trade: updated});
+            }
+            return Ok(old);
+        }
+        self.trades.update_async(trade_id, |k, v| {                     //写入失败 恢复内存 之后已经有新的修改则只记录日志
+            if *v == updated {
+                self.reindex(k, v, &old);
+                *v = old.clone();
+            } else {
+                log::error!("trade {} store failed but changed again {:?}", k, v);
             }
-            v.clone()
-        }).await
+        }).await;
+        Err(LedgerError::StorageFailure(format!("update trade {}", trade_id)))
+    }
+    pub async fn list_approving(&self)-> Vec<(StaticStr, Trade)> {
+        self.list(&self.approving).await
+    }
+    pub async fn list_waiting(&self)-> Vec<(StaticStr, Trade)> {
+        self.list(&self.waiting).await
+    }
+    async fn list(&self, set: &HashSet<StaticStr>)-> Vec<(StaticStr, Trade)> {
+        let mut ids = Vec::new();
+        set.scan_async(|id| ids.push(id.clone()) ).await;
+        let mut trades = Vec::new();
+        for id in ids {
+            if let Some(trade) = self.trade(&id).await { trades.push((id, trade)); }
+        }
+        trades
+    }
+}
+
+pub async fn find_by_hash(hash: &StaticStr)-> Vec<(u32, StaticStr, Trade)> {
+    let ids = HASHES.get_async(hash).await.map(|ids| ids.clone() ).unwrap_or_default();
+    let mut trades = Vec::new();
+    for (asset, id) in ids {
+        let Ok(manager) = manager(asset) else { continue };
+        if let Some(trade) = manager.trade(&id).await { trades.push((asset, id, trade)); }
+    }
+    trades
+}
+
+static FUND_HASHES: Lazy<HashMap<StaticStr, (u32, StaticStr)>> = Lazy::new(HashMap::default);     //正在使用的充值 hash -> (asset, trade_id) 保存完成之前占用
+
+pub(crate) struct ReservedHash(StaticStr);
+
+impl Drop for ReservedHash {                    //交易保存之后 HASHES 中已经有这个 hash 再释放
+    fn drop(&mut self) {
+        let _ = FUND_HASHES.remove(&self.0);
     }
 }
+
+pub(crate) async fn reserve_fund_hash(hash: &StaticStr, asset: u32, trade_id: &StaticStr)-> LedgerResult<Option<ReservedHash>> {      //先占用再检查 相同 hash 的并发充值只有一个成功
+    if hash.is_empty() { return Ok(None) }
+    if let Err((hash, _)) = FUND_HASHES.insert_async(hash.clone(), (asset, trade_id.clone())).await {
+        let (asset, trade_id) = FUND_HASHES.read_async(&hash, |_, used| used.clone() ).await.unwrap_or((asset, trade_id.clone()));
+        return Err(LedgerError::DuplicateHash{hash, asset, trade_id});
+    }
+    let reserved = ReservedHash(hash.clone());
+    if let Some((asset, trade_id)) = fund_hash_used(hash, trade_id).await { return Err(LedgerError::DuplicateHash{hash: hash.clone(), asset, trade_id}); }
+    Ok(Some(reserved))
+}
+
+pub async fn fund_hash_used(hash: &StaticStr, trade_id: &StaticStr)-> Option<(u32, StaticStr)> {       //同一个 hash 只能对应一笔充值
+    if hash.is_empty() { return None }
+    find_by_hash(hash).await.into_iter().find(|(_, id, trade)| id != trade_id && trade.r#type == TransferType::Fund ).map(|(asset, id, _)| (asset, id) )
+}
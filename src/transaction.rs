@@ -0,0 +1,303 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+use once_cell::sync::Lazy;
+use scc::HashMap;
+use serde::{Deserialize, Serialize};
+use super::trade::{self, GasInfo, StaticStr, Trade, TransferType, TransferStatus};
+use super::store::{meta_write, BACKEND};
+use super::error::{LedgerError, LedgerResult};
+use super::{asset, id, journal, wal, ACCOUNTS, GATE};
+
+const TRANSACTIONS_KEY: &str = "@transactions";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Leg {                                //事务中的一笔 pay 或者 withdraw
+    pub asset: u32,
+    #[serde(default)]
+    pub trade_id: Option<StaticStr>,            //不提供则使用 snowflake 生成
+    pub r#type: TransferType,
+    pub from: StaticStr,
+    pub to: StaticStr,
+    pub amount: u64,
+    #[serde(default)]
+    pub gas: Vec<GasInfo>,
+    #[serde(default)]
+    pub hash: StaticStr,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum TransactionStatus {
+    Pending,                                    //资金已经锁定 等待一起完成
+    Committed,
+    Aborted,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Transaction {
+    pub id: StaticStr,
+    pub legs: Vec<(u32, StaticStr)>,            //(asset, trade_id)
+    pub status: TransactionStatus,
+    pub create_tick: i64,
+    pub update_tick: i64,
+}
+
+struct Registry {
+    transactions: RwLock<Vec<Transaction>>,    //下标就是在 meta list 中的位置
+    positions: HashMap<StaticStr, usize>,       //事务 id -> 下标
+    pending: HashMap<(u32, StaticStr), StaticStr>,      //Pending 事务中的交易 -> 事务 id 完成之后删除
+    writing: tokio::sync::Mutex<()>,            //写入 store 的时候不持有 transactions 的锁 写入之间串行
+}
+
+impl Registry {
+    fn load()-> Self {
+        let transactions: Vec<Transaction> = BACKEND.meta().list(TRANSACTIONS_KEY).iter().filter_map(|buf| rmp_serde::from_slice(buf).ok() ).collect();
+        let registry = Self{transactions: RwLock::new(Vec::new()), positions: HashMap::default(), pending: HashMap::default(), writing: tokio::sync::Mutex::new(())};
+        for (i, tx) in transactions.iter().enumerate() {
+            registry.index(i, tx);
+        }
+        *registry.transactions.write().unwrap() = transactions;
+        registry
+    }
+
+    fn index(&self, position: usize, tx: &Transaction) {
+        let _ = self.positions.insert(tx.id.clone(), position);
+        for leg in &tx.legs {
+            if tx.status == TransactionStatus::Pending {
+                let _ = self.pending.insert(leg.clone(), tx.id.clone());
+            } else {
+                let _ = self.pending.remove(leg);
+            }
+        }
+    }
+
+    fn get(&self, tx_id: &str)-> Option<Transaction> {
+        let position = self.positions.read(tx_id, |_, p| *p )?;
+        self.transactions.read().unwrap().get(position).cloned()
+    }
+
+    async fn add(&self, tx: Transaction)-> LedgerResult<()> {
+        let _writing = self.writing.lock().await;
+        if self.positions.contains(&tx.id) { return Err(LedgerError::InvalidTransaction(format!("{} existed", tx.id))); }
+        let buf = rmp_serde::to_vec(&tx)?;
+        if !meta_write(move |meta| meta.push(TRANSACTIONS_KEY, &buf) ).await { return Err(LedgerError::StorageFailure(format!("store transaction {}", tx.id))); }
+        let mut transactions = self.transactions.write().unwrap();
+        self.index(transactions.len(), &tx);
+        transactions.push(tx);
+        Ok(())
+    }
+
+    async fn set_status(&self, tx_id: &str, status: TransactionStatus)-> LedgerResult<()> {
+        let _writing = self.writing.lock().await;
+        let index = self.positions.read(tx_id, |_, p| *p ).ok_or(LedgerError::UnknownTransaction(Cow::from(tx_id.to_string())))?;
+        let mut tx = self.transactions.read().unwrap()[index].clone();
+        tx.status = status;
+        tx.update_tick = chrono::Utc::now().timestamp();
+        let buf = rmp_serde::to_vec(&tx)?;
+        if !meta_write(move |meta| meta.set_at(TRANSACTIONS_KEY, index, &buf) ).await { return Err(LedgerError::StorageFailure(format!("store transaction {}", tx_id))); }
+        self.index(index, &tx);
+        self.transactions.write().unwrap()[index] = tx;
+        Ok(())
+    }
+}
+
+static TRANSACTIONS: Lazy<Registry> = Lazy::new(Registry::load);
+static TX_LOCK: Lazy<tokio::sync::Mutex<()>> = Lazy::new(|| tokio::sync::Mutex::new(()) );    //commit 和 abort 串行 避免同一个事务同时完成
+
+pub fn get_transaction(tx_id: &str)-> Option<Transaction> {
+    TRANSACTIONS.get(tx_id)
+}
+
+pub fn list_transactions(pending: bool)-> Vec<Transaction> {
+    TRANSACTIONS.transactions.read().unwrap().iter().filter(|t| !pending || t.status == TransactionStatus::Pending ).cloned().collect()
+}
+
+pub fn owner(asset: u32, trade_id: &StaticStr)-> Option<StaticStr> {      //交易属于未完成的事务时 返回事务 id 不能单独完成
+    TRANSACTIONS.pending.read(&(asset, trade_id.clone()), |_, tx| tx.clone() )
+}
+
+async fn check_balances(trades: &[(u32, StaticStr, Trade)])-> LedgerResult<()> {       //同一个账户同一个资产的金额和 gas 合计之后检查
+    let mut needed: BTreeMap<(StaticStr, u32), u64> = BTreeMap::new();
+    for (asset, _, trade) in trades {
+        for (asset, amount) in std::iter::once((*asset, trade.amount)).chain(trade.gas.iter().map(|g| (g.asset, g.amount) )) {
+            let total = needed.entry((trade.from.clone(), asset)).or_default();
+            *total = total.checked_add(amount).ok_or(LedgerError::Overflow{asset})?;
+        }
+    }
+    for ((from, asset), needed) in needed {
+        let available = ACCOUNTS.read_async(&from, |_, account| account.amount(asset as usize).0 ).await.unwrap_or(0);
+        if available < needed { return Err(LedgerError::InsufficientBalance{asset, needed, available}); }
+    }
+    Ok(())
+}
+
+async fn unlock(trades: &[(u32, StaticStr, Trade)], forget: bool) {
+    for (asset, trade_id, trade) in trades {
+        if let Err(e) = super::account_modify(&trade.from, |account| account.rollback(*asset as usize, trade) ).await { log::error!("unlock {} {:?}", trade_id, e); }
+        if forget {
+            super::account_forget(&trade.from, *asset, trade_id).await;
+            for to in trade.counterparties() {
+                super::account_forget(&to, *asset, trade_id).await;
+            }
+        }
+    }
+}
+
+pub async fn begin_transaction(legs: Vec<Leg>)-> LedgerResult<StaticStr> {     //全部锁定或者全部不锁定 返回事务 id
+    let _gate = GATE.read().await;
+    let tx_id = id::next_trade_id()?;
+    if legs.is_empty() { return Err(LedgerError::InvalidTransaction(format!("{} has no legs", tx_id))); }
+    let mut trades: Vec<(u32, StaticStr, Trade)> = Vec::new();
+    let mut reserved = Vec::new();                  //所有的 id 保存完成之前占用
+    for leg in legs {
+        asset::check_active(leg.asset)?;
+        super::check_gas(&leg.gas)?;
+        let trade_id = match leg.trade_id {
+            Some(id)=> id,
+            None=> id::next_trade_id()?,
+        };
+        reserved.push(trade::manager(leg.asset)?.reserve(&trade_id).await?);
+        let trade = match leg.r#type {
+            TransferType::Pay=> Trade::pay(leg.from, leg.to, leg.amount, leg.gas, leg.hash),
+            TransferType::Withdraw=> Trade::withdraw(leg.from, leg.to, leg.amount, leg.gas, leg.hash),
+            t=> return Err(LedgerError::InvalidTransaction(format!("{} leg {} type {:?} is not pay or withdraw", tx_id, trade_id, t))),
+        };
+        trades.push((leg.asset, trade_id, trade));
+    }
+    check_balances(&trades).await?;
+    let now = chrono::Utc::now().timestamp();
+    TRANSACTIONS.add(Transaction{id: tx_id.clone(), legs: trades.iter().map(|t| (t.0, t.1.clone()) ).collect(), status: TransactionStatus::Pending, create_tick: now, update_tick: now}).await?;
+
+    let mut pendings = Vec::new();
+    for (i, (asset, trade_id, trade)) in trades.iter().enumerate() {       //检查之后余额仍然可能被其他操作使用 锁定失败时解锁之前的
+        let locked = match wal::begin(wal::Op::Start{asset: *asset, trade_id: trade_id.clone(), trade: trade.clone()}).await {
+            Ok(pending)=> {
+                pendings.push(pending);
+                super::account_start(*asset, trade_id.clone(), trade).await
+            }
+            Err(e)=> Err(e),
+        };
+        if let Err(e) = locked {
+            unlock(&trades[..i], true).await;
+            TRANSACTIONS.set_status(&tx_id, TransactionStatus::Aborted).await?;
+            return Err(e);
+        }
+        for to in trade.counterparties() {
+            super::account_add(to, *asset, trade_id.clone(), trade.create_tick, None).await;
+        }
+    }
+
+    for (i, (asset, trade_id, trade)) in trades.iter().enumerate() {       //保存失败时 已经保存的改为 Failed 其余的删除记录
+        if let Err(e) = trade::manager(*asset)?.insert(trade_id.clone(), trade.clone()).await {
+            for (asset, trade_id, _) in &trades[..i] {
+                if let Err(e) = trade::manager(*asset)?.update(trade_id, |trade| trade.fail() ).await { log::error!("fail {} {:?}", trade_id, e); }
+            }
+            unlock(&trades[..i], false).await;
+            unlock(&trades[i..], true).await;
+            TRANSACTIONS.set_status(&tx_id, TransactionStatus::Aborted).await?;
+            return Err(e);
+        }
+    }
+    for (asset, trade_id, trade) in &trades {
+        journal::record(journal::lock(*asset, trade_id, trade)).await;
+    }
+    Ok(tx_id)
+}
+
+async fn complete(tx_id: StaticStr, success: bool)-> LedgerResult<()> {
+    let _gate = GATE.read().await;
+    let _lock = TX_LOCK.lock().await;
+    let tx = TRANSACTIONS.get(&tx_id).ok_or(LedgerError::UnknownTransaction(tx_id.clone()))?;
+    if tx.status != TransactionStatus::Pending { return Err(LedgerError::InvalidTransaction(format!("{} is {:?}", tx_id, tx.status))); }
+    let done = if success { TransferStatus::Succeeded } else { TransferStatus::Failed };
+    let mut open = Vec::new();
+    for (asset, trade_id) in &tx.legs {          //重试时跳过已经完成的 commit 要求所有的交易都已经保存
+        match trade::manager(*asset)?.trade(trade_id).await {
+            Some(trade) if trade.status == TransferStatus::Pending=> open.push((*asset, trade_id.clone(), trade)),
+            Some(trade) if trade.status == done=> {}
+            None if !success=> {}                                   //begin 中途崩溃 没有保存的交易
+            other=> return Err(LedgerError::InvalidTransaction(format!("{} leg {} is {:?}", tx_id, trade_id, other.map(|t| t.status )))),
+        }
+    }
+    super::check_completed(&open.iter().map(|(asset, _, trade)| (*asset, trade) ).collect::<Vec<_>>(), success).await?;      //所有的交易一起试做 一个失败就都不修改
+    let mut failed = None;
+    for (asset, trade_id, _) in open {
+        if let Err(e) = super::finish_transfer(asset, trade_id.clone(), success).await {
+            log::error!("transaction {} leg {} {:?}", tx_id, trade_id, e);
+            failed.get_or_insert(e);
+        }
+    }
+    if let Some(e) = failed { return Err(e) }       //保持 Pending 可以重试
+    TRANSACTIONS.set_status(&tx_id, if success { TransactionStatus::Committed } else { TransactionStatus::Aborted }).await
+}
+
+pub async fn commit_transaction(tx_id: StaticStr)-> LedgerResult<()> {         //所有的交易一起成功
+    complete(tx_id, true).await
+}
+
+pub async fn abort_transaction(tx_id: StaticStr)-> LedgerResult<()> {          //所有的交易一起失败 回滚锁定的资金
+    complete(tx_id, false).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+
+    fn s(v: &str)-> StaticStr {
+        Cow::from(v.to_string())
+    }
+
+    fn leg(trade_id: &str, from: &str, to: &str, amount: u64)-> Leg {
+        Leg{asset: 0, trade_id: Some(s(trade_id)), r#type: TransferType::Pay, from: s(from), to: s(to), amount, gas: Vec::new(), hash: s("")}
+    }
+
+    async fn fund(trade_id: &str, to: &str, amount: u64) {
+        super::super::add_fund(0, s(trade_id), s("x"), s(to), amount, Vec::new(), s("")).await.unwrap();
+        super::super::mark_broadcast(0, s(trade_id), s(&format!("{}-hash", trade_id))).await.unwrap();
+        super::super::complete_fund(0, s(trade_id), true).await.unwrap();
+    }
+
+    async fn amount(account: &str)-> Option<(u64, u64)> {
+        super::super::get_amount(&s(account)).await.map(|a| a[0] )
+    }
+
+    async fn status(trade_id: &str)-> Option<TransferStatus> {
+        trade::manager(0).unwrap().trade(&s(trade_id)).await.map(|t| t.status )
+    }
+
+    #[tokio::test]
+    async fn begin_locks_all_or_none() {
+        crate::test_init();
+        fund("tb-f", "tb-alice", 10).await;
+        assert!(matches!(begin_transaction(vec![leg("tb-l1", "tb-alice", "tb-bob", 6), leg("tb-l2", "tb-alice", "tb-bob", 6)]).await, Err(LedgerError::InsufficientBalance{asset: 0, needed: 12, available: 10})));
+        assert!(matches!(begin_transaction(vec![leg("tb-l1", "tb-alice", "tb-bob", 1), leg("tb-l1", "tb-alice", "tb-bob", 1)]).await, Err(LedgerError::DuplicateTrade(_))));
+        assert_eq!(amount("tb-alice").await, Some((10, 0)));
+        assert_eq!(status("tb-l1").await, None);
+
+        let tx_id = begin_transaction(vec![leg("tb-l1", "tb-alice", "tb-bob", 6), leg("tb-l2", "tb-alice", "tb-carol", 4)]).await.unwrap();     //失败时没有占用 id
+        assert_eq!(amount("tb-alice").await, Some((0, 10)));
+        assert!(matches!(super::super::complete_pay(0, s("tb-l1"), true).await, Err(LedgerError::InTransaction{..})));
+        commit_transaction(tx_id.clone()).await.unwrap();
+        assert_eq!(get_transaction(&tx_id).map(|t| t.status ), Some(TransactionStatus::Committed));
+        assert_eq!((amount("tb-alice").await, amount("tb-bob").await, amount("tb-carol").await), (Some((0, 0)), Some((6, 0)), Some((4, 0))));
+    }
+
+    #[tokio::test]
+    async fn failed_commit_changes_no_leg() {
+        crate::test_init();
+        fund("tc-f1", "tc-alice", 10).await;
+        fund("tc-f2", "tc-rich", u64::MAX).await;
+        let tx_id = begin_transaction(vec![leg("tc-l1", "tc-alice", "tc-carol", 1), leg("tc-l2", "tc-alice", "tc-rich", 1)]).await.unwrap();
+        assert!(matches!(commit_transaction(tx_id.clone()).await, Err(LedgerError::Overflow{asset: 0})));
+        assert_eq!((status("tc-l1").await, status("tc-l2").await), (Some(TransferStatus::Pending), Some(TransferStatus::Pending)));
+        assert_eq!(amount("tc-carol").await, Some((0, 0)));
+        assert_eq!(amount("tc-alice").await, Some((8, 2)));
+
+        abort_transaction(tx_id.clone()).await.unwrap();
+        assert_eq!(get_transaction(&tx_id).map(|t| t.status ), Some(TransactionStatus::Aborted));
+        assert_eq!((status("tc-l1").await, status("tc-l2").await), (Some(TransferStatus::Failed), Some(TransferStatus::Failed)));
+        assert_eq!(amount("tc-alice").await, Some((10, 0)));
+        assert_eq!(amount("tc-rich").await, Some((u64::MAX, 0)));
+    }
+}
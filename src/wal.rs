@@ -0,0 +1,98 @@
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use super::trade::{self, StaticStr, Trade, TransferType, TransferStatus};
+use super::error::{LedgerError, LedgerResult};
+use super::{config, journal};
+use super::store::BACKEND;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Op {
+    Start{asset: u32, trade_id: StaticStr, trade: Trade},         //锁定资金 保存交易
+    Fund{asset: u32, trade_id: StaticStr, trade: Trade},          //保存充值交易
+    Complete{asset: u32, trade_id: StaticStr, success: bool},     //修改状态 入账或者回滚 包括 reject
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WalEntry {
+    pub op: Op,
+    pub tick: i64,
+}
+
+static WAL: Lazy<Option<sled::Db>> = Lazy::new(|| {                   //本地目录 不能和 sled store 共用
+    let path = config::get().wal.as_ref()?;
+    match sled::open(path) {
+        Ok(db)=> Some(db),
+        Err(e)=> {
+            log::error!("open wal {} {:?}", path, e);
+            None
+        }
+    }
+});
+
+pub struct Pending(Option<[u8; 8]>);
+
+impl Drop for Pending {                         //操作完成或者已经补偿之后删除 进程崩溃时保留 启动时恢复
+    fn drop(&mut self) {
+        if let (Some(key), Some(wal)) = (self.0, WAL.as_ref()) {
+            let _ = wal.remove(key);
+        }
+    }
+}
+
+pub async fn begin(op: Op)-> LedgerResult<Pending> {                   //在修改内存和 store 之前写入并 flush
+    let Some(wal) = WAL.as_ref() else { return Ok(Pending(None)) };
+    let failed = |e: sled::Error| LedgerError::StorageFailure(format!("wal {:?}", e));
+    let key = wal.generate_id().map_err(failed)?.to_be_bytes();
+    wal.insert(key, rmp_serde::to_vec(&WalEntry{op, tick: chrono::Utc::now().timestamp()})?).map_err(failed)?;
+    wal.flush_async().await.map_err(failed)?;
+    Ok(Pending(Some(key)))
+}
+
+fn recorded(account: &str, trade_id: &StaticStr, reason: journal::Reason)-> bool {
+    journal::get_journal(account).iter().any(|e| e.trade_id == *trade_id && e.reason == reason )
+}
+
+fn recover_op(op: &Op)-> &'static str {         //内存由 store 和快照重建 只需要补齐 journal
+    match op {
+        Op::Start{asset, trade_id, trade} | Op::Fund{asset, trade_id, trade}=> {
+            let Some(stored) = trade::manager(*asset).ok().and_then(|m| m.store.get(trade_id) ) else { return "discard" };
+            if stored.r#type != TransferType::Fund && !recorded(&trade.from, trade_id, journal::Reason::Lock) {
+                journal::write(BACKEND.meta(), journal::lock(*asset, trade_id, trade));
+            }
+            "replay"
+        }
+        Op::Complete{asset, trade_id, success}=> {
+            let Some(stored) = trade::manager(*asset).ok().and_then(|m| m.store.get(trade_id) ) else { return "discard" };
+            match (&stored.r#type, &stored.status, success) {
+                (TransferType::Fund, TransferStatus::Succeeded, true)=> {
+                    if !recorded(&stored.to, trade_id, journal::Reason::Income) { journal::write(BACKEND.meta(), journal::fund(*asset, trade_id, &stored)); }
+                }
+                (TransferType::Fund, _, _)=> return "discard",
+                (_, TransferStatus::Succeeded, true)=> {
+                    if !recorded(&stored.from, trade_id, journal::Reason::Confirm) { journal::write(BACKEND.meta(), journal::confirm(*asset, trade_id, &stored)); }
+                }
+                (_, TransferStatus::Failed, false)=> {
+                    if !recorded(&stored.from, trade_id, journal::Reason::Rollback) { journal::write(BACKEND.meta(), journal::rollback(*asset, trade_id, &stored)); }
+                }
+                _=> return "discard",                               //状态没有保存 操作没有发生
+            }
+            "replay"
+        }
+    }
+}
+
+pub fn recover()-> usize {                      //load_all 之后调用 返回处理的未完成操作数量
+    let Some(wal) = WAL.as_ref() else { return 0 };
+    let mut count = 0;
+    for item in wal.iter() {
+        let Ok((key, value)) = item else { continue };
+        match rmp_serde::from_slice::<WalEntry>(&value) {
+            Ok(entry)=> log::warn!("wal {} {:?}", recover_op(&entry.op), entry),
+            Err(e)=> log::error!("wal entry {:?} {:?}", key, e),
+        }
+        let _ = wal.remove(key);
+        count += 1;
+    }
+    let _ = wal.flush();
+    count
+}
@@ -0,0 +1,48 @@
+use std::borrow::Cow;
+use account::trade::{self, StaticStr, Trade, TransferStatus};
+use account::{config, snapshot};
+
+fn s(v: &str)-> StaticStr {
+    Cow::from(v.to_string())
+}
+
+fn restart() {                                  //丢弃内存中的交易 和重启一样从 store 加载
+    for manager in trade::managers() {
+        manager.trades.clear();
+        manager.approving.clear();
+        manager.waiting.clear();
+    }
+    trade::HASHES.clear();
+    account::load_all();
+}
+
+async fn amount(account: &str)-> Option<(u64, u64)> {
+    account::get_amount(&s(account)).await.map(|a| a[0] )
+}
+
+#[tokio::test]
+async fn restore_replays_only_newer_trades() {              //单独的进程 重启不影响其他测试
+    let log = config::LogConfig{level: "off".to_string(), file: None};
+    config::init(config::Config{store: "memory".to_string(), log, ..Default::default()}).unwrap();
+    let mut fund = Trade::fund(s("x"), s("alice"), 100, Vec::new(), s("h1"));
+    fund.status = TransferStatus::Succeeded;
+    let manager = trade::manager(0).unwrap();
+    assert!(manager.store.insert(&s("f1"), &fund));
+    assert!(manager.store.insert(&s("f1"), &fund));          //旧版本留下的重复条目
+    account::load_all();
+    assert_eq!(amount("alice").await, Some((100, 0)));
+
+    account::add_pay(0, s("p1"), s("alice"), s("bob"), 30, Vec::new(), s("")).await.unwrap();
+    account::complete_pay(0, s("p1"), true).await.unwrap();
+    account::add_pay(0, s("p2"), s("alice"), s("bob"), 20, Vec::new(), s("")).await.unwrap();
+    assert!(snapshot::take_snapshot().await);
+    account::complete_pay(0, s("p2"), true).await.unwrap();        //快照之后完成 按照 open 补做
+    account::add_pay(0, s("p3"), s("alice"), s("carol"), 5, Vec::new(), s("")).await.unwrap();
+
+    restart();
+    assert_eq!(amount("alice").await, Some((45, 5)));
+    assert_eq!(amount("bob").await, Some((50, 0)));
+    assert_eq!(amount("carol").await, Some((0, 0)));
+    account::complete_pay(0, s("p3"), true).await.unwrap();
+    assert_eq!(amount("carol").await, Some((5, 0)));
+}